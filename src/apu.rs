@@ -0,0 +1,642 @@
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+use self::noise::Noise;
+use self::output::SampleOutput;
+use self::pulse::Pulse;
+use self::wave::Wave;
+
+mod noise;
+mod output;
+mod pulse;
+mod units;
+mod wave;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+// system counter bit whose falling edge clocks the frame sequencer at 512 Hz
+pub const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+pub const FRAME_SEQUENCER_BIT_DOUBLE_SPEED: u16 = 1 << 13;
+
+const CLOCK_RATE: u32 = 4_194_304;  // clock cycles per second, at single speed
+const STEP_CYCLES: u8 = 2;          // the channels change at most every other clock cycle
+
+const NR52_POWER: u8 = 0x80;
+
+/* ----- TYPE DECLARATIONS ----- */
+// numbered from 0 like the stems
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Channel {
+    Pulse1 = 0,
+    Pulse2 = 1,
+    Wave = 2,
+    Noise = 3,
+}
+
+// what a channel is doing right now, for visualizers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ChannelState {
+    pub enabled: bool,      // playing, as in NR52
+    pub dac_enabled: bool,
+    pub frequency: u16,     // the 11-bit frequency register, NR43 for the noise channel
+    pub hz: f32,            // tone frequency, LFSR clock for the noise channel
+    pub volume: u8,         // 0-15
+    pub duty: Option<u8>,   // NRx1 duty 0-3, pulse channels only
+    pub left: bool,
+    pub right: bool,
+    pub audible: bool,      // false when muted or another channel is soloed
+}
+
+pub struct Apu {
+    model: Model,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    frame_step: u8,     // next frame sequencer step, 0-7
+    power: bool,
+    nr50: u8,           // master volume, VIN bits are stored but nothing drives VIN
+    nr51: u8,           // panning, high nibble left and low nibble right
+    output: Option<SampleOutput>,
+    stems: Vec<SampleOutput>,   // one output per channel, empty unless enabled
+    muted: u8,          // channel bits left out of the mix
+    soloed: u8,         // channel bits that are the only ones mixed, when any are set
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Apu {
+    pub fn new(model: Model) -> Apu {
+        Apu {
+            model,
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            wave: Wave::new(model),
+            noise: Noise::new(),
+            frame_step: 0,
+            power: false,
+            nr50: 0,
+            nr51: 0,
+            output: None,
+            stems: Vec::new(),
+            muted: 0,
+            soloed: 0,
+        }
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    // the boot ROM powers the APU up and ends with the tail of its chime on channel 1
+    pub fn boot(&mut self) {
+        let registers = [(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xF3),
+            (0xFF11, 0x80), (0xFF12, 0xF3), (0xFF13, 0xC1), (0xFF14, 0x87)];
+        for (address, value) in registers {
+            self.write_register(address, value);
+        }
+    }
+
+    // start producing samples at the host's rate, dropping any not read yet
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.output = Some(SampleOutput::new(CLOCK_RATE, sample_rate, self.model.is_cgb()));
+        if !self.stems.is_empty() {
+            self.enable_stems();
+        }
+    }
+
+    // also produce each channel on its own, panned and scaled like in the mix,
+    // at the sample rate already set
+    pub fn enable_stems(&mut self) {
+        if let Some(sample_rate) = self.get_sample_rate() {
+            let cgb = self.model.is_cgb();
+            self.stems = (0..4).map(|_| SampleOutput::new(CLOCK_RATE, sample_rate, cgb)).collect();
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> Option<u32> {
+        self.output.as_ref().map(|output| output.get_sample_rate())
+    }
+
+    // stereo frames ready to be read, none until a sample rate is set
+    pub fn samples_available(&self) -> usize {
+        self.output.as_ref().map_or(0, |output| output.available())
+    }
+
+    // fills out with interleaved left and right samples in -1.0 to 1.0,
+    // returns the number of frames written
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.output.as_mut().map_or(0, |output| output.read(out))
+    }
+
+    pub fn read_samples_i16(&mut self, out: &mut [i16]) -> usize {
+        let mut samples = vec![0.0; out.len()];
+        let frames = self.read_samples(&mut samples);
+        convert_i16(&samples[..frames * 2], out);
+        frames
+    }
+
+    // the same for one channel's stem, channels numbered from 0
+    pub fn read_stem_samples(&mut self, channel: usize, out: &mut [f32]) -> usize {
+        self.stems.get_mut(channel).map_or(0, |stem| stem.read(out))
+    }
+
+    pub fn read_stem_samples_i16(&mut self, channel: usize, out: &mut [i16]) -> usize {
+        let mut samples = vec![0.0; out.len()];
+        let frames = self.read_stem_samples(channel, &mut samples);
+        convert_i16(&samples[..frames * 2], out);
+        frames
+    }
+
+    // mute and solo only change what is mixed, the channels keep running and the stems
+    // keep recording them
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted = set_bit(self.muted, channel, muted);
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted & channel.bit() != 0
+    }
+
+    // while any channel is soloed, only soloed channels are heard
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool) {
+        self.soloed = set_bit(self.soloed, channel, soloed);
+    }
+
+    pub fn is_soloed(&self, channel: Channel) -> bool {
+        self.soloed & channel.bit() != 0
+    }
+
+    pub fn get_channel_state(&self, channel: Channel) -> ChannelState {
+        let (enabled, dac_enabled, frequency, hz, volume, duty) = match channel {
+            Channel::Pulse1 | Channel::Pulse2 => {
+                let pulse = if channel == Channel::Pulse1 { &self.pulse1 } else { &self.pulse2 };
+                (pulse.is_enabled(), pulse.is_dac_enabled(), pulse.get_frequency(), pulse.get_hz(),
+                    pulse.get_volume(), Some(pulse.get_duty()))
+            },
+            Channel::Wave => (self.wave.is_enabled(), self.wave.is_dac_enabled(), self.wave.get_frequency(),
+                self.wave.get_hz(), self.wave.get_volume(), None),
+            Channel::Noise => (self.noise.is_enabled(), self.noise.is_dac_enabled(),
+                self.noise.get_register() as u16, self.noise.get_hz(), self.noise.get_volume(), None),
+        };
+        ChannelState {
+            enabled,
+            dac_enabled,
+            frequency,
+            hz,
+            volume,
+            duty,
+            left: self.nr51 & (channel.bit() << 4) != 0,
+            right: self.nr51 & channel.bit() != 0,
+            audible: self.audible() & channel.bit() != 0,
+        }
+    }
+
+    // advance the channels by clock cycles at single speed
+    pub fn tick(&mut self, cycles: u8) {
+        if self.output.is_none() {
+            self.tick_channels(cycles as u16);
+            return;
+        }
+
+        // sample the mixer every time a channel can change
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let step = remaining.min(STEP_CYCLES);
+            remaining -= step;
+            self.tick_channels(step as u16);
+            let analog = self.analog();
+            let level = self.mix(analog, self.audible());
+            if let Some(output) = self.output.as_mut() {
+                output.set_level(level);
+                output.advance(step as u32);
+            }
+            for channel in 0..self.stems.len() {
+                let level = self.mix(analog, 1 << channel);
+                self.stems[channel].set_level(level);
+                self.stems[channel].advance(step as u32);
+            }
+        }
+    }
+
+    // a falling edge of the DIV bit, steps 0, 2, 4 and 6 clock length counters,
+    // 2 and 6 the sweep, and 7 the envelopes
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+        if self.frame_step & 0x01 == 0 {
+            self.pulse1.clock_length();
+            self.pulse2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.pulse1.clock_envelope();
+            self.pulse2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    // PCM12, the digital outputs of channels 1 and 2 in the low and high nibble
+    pub fn read_pcm12(&self) -> u8 {
+        self.pulse1.output() | (self.pulse2.output() << 4)
+    }
+
+    // PCM34, the same for channels 3 and 4
+    pub fn read_pcm34(&self) -> u8 {
+        self.wave.output() | (self.noise.output() << 4)
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF10..=0xFF14 => self.pulse1.read_register((address - 0xFF10) as u8),
+            0xFF15..=0xFF19 => self.pulse2.read_register((address - 0xFF15) as u8),
+            0xFF1A..=0xFF1E => self.wave.read_register((address - 0xFF1A) as u8),
+            0xFF1F..=0xFF23 => self.noise.read_register((address - 0xFF1F) as u8),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => 0x70 | if self.power { NR52_POWER } else { 0x00 } | self.channel_status(),
+            0xFF30..=0xFF3F => self.wave.read_ram((address - 0xFF30) as usize),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if address == 0xFF26 {
+            self.write_power(value);
+            return;
+        }
+        let length_step_next = self.frame_step & 0x01 == 0;
+        if !self.power {
+            // only wave RAM is writable, plus the length counters on models that keep them
+            let length = self.model.keeps_length_on_power_off();
+            match address {
+                0xFF11 | 0xFF16 | 0xFF20 if length => {
+                    self.write_register_powered(address, value & 0x3F, length_step_next)
+                },
+                0xFF1B if length => self.write_register_powered(address, value, length_step_next),
+                0xFF30..=0xFF3F => self.wave.write_ram((address - 0xFF30) as usize, value),
+                _ => {},
+            }
+            return;
+        }
+        self.write_register_powered(address, value, length_step_next);
+    }
+
+    // the emulated hardware, not the sample output or mute and solo
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.power);
+        state.write_u8(self.nr50);
+        state.write_u8(self.nr51);
+        state.write_u8(self.frame_step);
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.power = state.read_bool()?;
+        self.nr50 = state.read_u8()?;
+        self.nr51 = state.read_u8()?;
+        self.frame_step = state.read_u8()? & 0x07;
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)
+    }
+
+    /* ----- PRIVATE ----- */
+    fn write_register_powered(&mut self, address: u16, value: u8, length_step_next: bool) {
+        match address {
+            0xFF10..=0xFF14 => {
+                self.pulse1.write_register((address - 0xFF10) as u8, value, length_step_next)
+            },
+            0xFF16..=0xFF19 => {
+                self.pulse2.write_register((address - 0xFF15) as u8, value, length_step_next)
+            },
+            0xFF1A..=0xFF1E => {
+                self.wave.write_register((address - 0xFF1A) as u8, value, length_step_next)
+            },
+            0xFF20..=0xFF23 => {
+                self.noise.write_register((address - 0xFF1F) as u8, value, length_step_next)
+            },
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+            0xFF30..=0xFF3F => self.wave.write_ram((address - 0xFF30) as usize, value),
+            _ => {},
+        }
+    }
+
+    // powering off clears every register, powering on restarts the frame sequencer
+    fn write_power(&mut self, value: u8) {
+        let power = value & NR52_POWER != 0;
+        if self.power && !power {
+            let keep_length = self.model.keeps_length_on_power_off();
+            self.pulse1.power_off(keep_length);
+            self.pulse2.power_off(keep_length);
+            self.wave.power_off(keep_length);
+            self.noise.power_off(keep_length);
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.power && power {
+            self.frame_step = 0;
+        }
+        self.power = power;
+    }
+
+    fn tick_channels(&mut self, cycles: u16) {
+        self.pulse1.tick(cycles);
+        self.pulse2.tick(cycles);
+        self.wave.tick(cycles);
+        self.noise.tick(cycles);
+    }
+
+    // each DAC turns its channel's 0-15 into -1.0 to 1.0
+    fn analog(&self) -> [f32; 4] {
+        [
+            dac(self.pulse1.is_dac_enabled(), self.pulse1.output()),
+            dac(self.pulse2.is_dac_enabled(), self.pulse2.output()),
+            dac(self.wave.is_dac_enabled(), self.wave.output()),
+            dac(self.noise.is_dac_enabled(), self.noise.output()),
+        ]
+    }
+
+    // the mixer adds up the panned channels picked by the mask and scales them by the
+    // master volume
+    fn mix(&self, analog: [f32; 4], channels: u8) -> [f32; 2] {
+        let mut level = [0.0; 2];
+        for (channel, value) in analog.iter().enumerate() {
+            if channels & (1 << channel) == 0 {
+                continue;
+            }
+            if self.nr51 & (0x10 << channel) != 0 {
+                level[0] += value;
+            }
+            if self.nr51 & (0x01 << channel) != 0 {
+                level[1] += value;
+            }
+        }
+        let left = ((self.nr50 >> 4) & 0x07) + 1;
+        let right = (self.nr50 & 0x07) + 1;
+        [level[0] * left as f32 / 32.0, level[1] * right as f32 / 32.0]
+    }
+
+    // channel bits that make it into the mix
+    fn audible(&self) -> u8 {
+        if self.soloed != 0 { self.soloed } else { !self.muted & 0x0F }
+    }
+
+    // NR52 bits 0-3, set while a channel is playing
+    fn channel_status(&self) -> u8 {
+        self.pulse1.is_enabled() as u8
+            | (self.pulse2.is_enabled() as u8) << 1
+            | (self.wave.is_enabled() as u8) << 2
+            | (self.noise.is_enabled() as u8) << 3
+    }
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [Channel::Pulse1, Channel::Pulse2, Channel::Wave, Channel::Noise];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+fn set_bit(mask: u8, channel: Channel, set: bool) -> u8 {
+    if set { mask | channel.bit() } else { mask & !channel.bit() }
+}
+
+fn dac(enabled: bool, output: u8) -> f32 {
+    if enabled { output as f32 / 7.5 - 1.0 } else { 0.0 }
+}
+
+fn convert_i16(samples: &[f32], out: &mut [i16]) {
+    for (sample, value) in out.iter_mut().zip(samples) {
+        *sample = (value * i16::MAX as f32) as i16;
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_masks() {
+        let apu = Apu::new(Model::Dmg);
+        let masks = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF, 0xFF, 0x00, 0x00, 0xBF,
+            0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        for (offset, mask) in masks.iter().enumerate() {
+            assert_eq!(apu.read_register(0xFF10 + offset as u16), *mask);
+        }
+    }
+
+    #[test]
+    fn test_frame_sequencer() {
+        let mut apu = Apu::new(Model::Dmg);
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF17, 0xF1);
+        apu.write_register(0xFF16, 0x3E);
+        apu.write_register(0xFF19, 0xC0);
+        assert!(apu.pulse2.is_enabled());
+
+        // two length clocks on steps 0 and 2 run out the counter
+        apu.clock_frame_sequencer();
+        assert!(apu.pulse2.is_enabled());
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert!(!apu.pulse2.is_enabled());
+
+        // the envelope steps on step 7
+        apu.write_register(0xFF19, 0x80);
+        for _ in 0..4 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.pulse2.get_volume(), 15);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.pulse2.get_volume(), 14);
+        assert!(apu.pulse2.is_enabled());
+    }
+
+    #[test]
+    fn test_power() {
+        let mut apu = Apu::new(Model::Dmg);
+        apu.boot();
+        assert_eq!(apu.read_register(0xFF26), 0xF1);
+        assert_eq!(apu.read_register(0xFF24), 0x77);
+        apu.write_register(0xFF30, 0x12);
+        apu.write_register(0xFF1B, 0xFF);
+
+        // powering off clears the registers and ignores writes, wave RAM survives
+        apu.write_register(0xFF26, 0x00);
+        assert_eq!(apu.read_register(0xFF26), 0x70);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        assert_eq!(apu.read_register(0xFF25), 0x00);
+        apu.write_register(0xFF12, 0xF0);
+        assert_eq!(apu.read_register(0xFF12), 0x00);
+        assert_eq!(apu.read_register(0xFF30), 0x12);
+
+        // the DMG keeps its length counters, and they stay writable
+        apu.write_register(0xFF16, 0xFF);
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0xC0);
+        assert_eq!(apu.read_register(0xFF26), 0xF2);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0xFF26), 0xF0);
+        assert_eq!(apu.read_register(0xFF16), 0x3F);
+
+        let mut apu = Apu::new(Model::Cgb);
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF26, 0x00);
+        apu.write_register(0xFF16, 0x3F);
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0xC0);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0xFF26), 0xF2);
+    }
+
+    #[test]
+    fn test_mixer() {
+        let mut apu = Apu::new(Model::Dmg);
+        apu.write_register(0xFF26, 0x80);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x80);
+        assert_eq!(apu.mix(apu.analog(), 0x0F), [0.0, 0.0]);
+
+        // channel 2 left only, at full and lowest volume
+        apu.write_register(0xFF25, 0x20);
+        apu.write_register(0xFF24, 0x70);
+        assert_eq!(apu.mix(apu.analog(), 0x0F), [0.25, 0.0]);
+        apu.write_register(0xFF24, 0x07);
+        assert_eq!(apu.mix(apu.analog(), 0x0F), [1.0 / 32.0, 0.0]);
+
+        // a DAC that is off contributes nothing
+        apu.write_register(0xFF25, 0xFF);
+        assert_eq!(apu.mix(apu.analog(), 0x0F), [1.0 / 32.0, 1.0 / 4.0]);
+    }
+
+    #[test]
+    fn test_sample_output() {
+        let mut apu = Apu::new(Model::Dmg);
+        assert_eq!(apu.samples_available(), 0);
+        apu.set_sample_rate(32_768);
+        assert_eq!(apu.get_sample_rate(), Some(32_768));
+        apu.boot();
+
+        // a 1 kHz square on channel 2, both sides
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF18, 0x83);
+        apu.write_register(0xFF19, 0x87);
+        for _ in 0..CLOCK_RATE / 10 / 4 {
+            apu.tick(4);
+        }
+        assert_eq!(apu.samples_available(), 3276);
+
+        let mut samples = vec![0; 2 * 4096];
+        assert_eq!(apu.read_samples_i16(&mut samples), 3276);
+        assert_eq!(apu.samples_available(), 0);
+        let peak = samples[1000..6552].iter().map(|sample| sample.unsigned_abs()).max().unwrap();
+        assert!(peak > 8000);
+        assert!(samples[6552..].iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn test_stems() {
+        let mut apu = Apu::new(Model::Dmg);
+        apu.enable_stems();
+        assert_eq!(apu.read_stem_samples(0, &mut [0.0; 2]), 0);
+        apu.set_sample_rate(48_000);
+        apu.enable_stems();
+        apu.boot();
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x87);
+        for _ in 0..CLOCK_RATE / 100 / 4 {
+            apu.tick(4);
+        }
+
+        // the stems add up to the mix, silent channels stay silent
+        let mut mix = vec![0.0; 960];
+        let mut stems = vec![vec![0.0; 960]; 4];
+        let frames = apu.read_samples(&mut mix);
+        assert!(frames >= 479);
+        for (channel, stem) in stems.iter_mut().enumerate() {
+            assert_eq!(apu.read_stem_samples(channel, stem), frames);
+        }
+        for index in 0..frames * 2 {
+            let total: f32 = stems.iter().map(|stem| stem[index]).sum();
+            assert!((total - mix[index]).abs() < 1e-4);
+        }
+        assert!(stems[1].iter().any(|&sample| sample.abs() > 0.1));
+        assert!(stems[2].iter().all(|&sample| sample == 0.0));
+        assert_eq!(apu.read_stem_samples(4, &mut mix), 0);
+    }
+
+    #[test]
+    fn test_mute_solo() {
+        let mut apu = Apu::new(Model::Dmg);
+        apu.set_sample_rate(48_000);
+        apu.boot();
+        apu.write_register(0xFF25, 0xFF);
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF19, 0x80);
+        let full = apu.mix(apu.analog(), apu.audible());
+
+        apu.set_muted(Channel::Pulse2, true);
+        assert!(apu.is_muted(Channel::Pulse2));
+        assert!(!apu.get_channel_state(Channel::Pulse2).audible);
+        assert_eq!(apu.mix(apu.analog(), apu.audible()), apu.mix(apu.analog(), 0x0D));
+        assert_ne!(apu.mix(apu.analog(), apu.audible()), full);
+
+        // solo wins over mute, and silences everything else
+        apu.set_soloed(Channel::Pulse2, true);
+        apu.set_soloed(Channel::Wave, true);
+        assert_eq!(apu.audible(), 0x06);
+        assert!(!apu.get_channel_state(Channel::Pulse1).audible);
+        apu.set_soloed(Channel::Pulse2, false);
+        apu.set_soloed(Channel::Wave, false);
+        apu.set_muted(Channel::Pulse2, false);
+        assert_eq!(apu.audible(), 0x0F);
+        assert!(!apu.is_soloed(Channel::Wave));
+    }
+
+    #[test]
+    fn test_channel_state() {
+        let mut apu = Apu::new(Model::Dmg);
+        apu.boot();
+        let state = apu.get_channel_state(Channel::Pulse1);
+        assert!(state.enabled);
+        assert_eq!(state.frequency, 0x7C1);
+        assert_eq!(state.hz, 131_072.0 / 63.0);
+        assert_eq!(state.volume, 15);
+        assert_eq!(state.duty, Some(2));
+        assert!(state.left && state.right);
+
+        apu.write_register(0xFF1A, 0x80);
+        apu.write_register(0xFF1C, 0x40);
+        apu.write_register(0xFF1D, 0x00);
+        apu.write_register(0xFF1E, 0x87);
+        let state = apu.get_channel_state(Channel::Wave);
+        assert!(state.enabled && state.dac_enabled);
+        assert_eq!(state.hz, 256.0);
+        assert_eq!(state.volume, 7);
+        assert_eq!(state.duty, None);
+        assert!(state.left && !state.right);
+
+        apu.write_register(0xFF22, 0x21);
+        let state = apu.get_channel_state(Channel::Noise);
+        assert!(!state.enabled);
+        assert_eq!(state.frequency, 0x21);
+        assert_eq!(state.hz, 65_536.0);
+        assert!(state.left && !state.right);
+        assert_eq!(Channel::ALL.len(), 4);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use super::units::{Envelope, LengthCounter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+// clock cycles per LFSR step before the shift, indexed by NR43 bits 0-2
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+const NR43_WIDTH: u8 = 0x08;    // 7-bit LFSR
+
+/* ----- TYPE DECLARATIONS ----- */
+// channel 4 outputs the low bit of a linear feedback shift register
+pub(super) struct Noise {
+    enabled: bool,
+    register: u8,   // NR43 as written
+    lfsr: u16,
+    timer: u32,     // clock cycles until the next LFSR step
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Noise {
+    pub(super) fn new() -> Noise {
+        Noise {
+            enabled: false,
+            register: 0,
+            lfsr: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn is_dac_enabled(&self) -> bool {
+        self.envelope.is_dac_enabled()
+    }
+
+    // every register cleared, keep_length leaves the length counter as it is
+    pub(super) fn power_off(&mut self, keep_length: bool) {
+        *self = Noise {
+            length: self.length.power_off(keep_length),
+            ..Noise::new()
+        };
+    }
+
+    pub(super) fn get_register(&self) -> u8 {
+        self.register
+    }
+
+    // LFSR steps per second
+    pub(super) fn get_hz(&self) -> f32 {
+        if self.register >> 4 >= 14 { 0.0 } else { 4_194_304.0 / self.period() as f32 }
+    }
+
+    pub(super) fn get_volume(&self) -> u8 {
+        self.envelope.get_volume()
+    }
+
+    // digital output 0-15, the volume while the low bit of the LFSR is clear
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 0x01 == 0 { self.get_volume() } else { 0 }
+    }
+
+    pub(super) fn tick(&mut self, cycles: u16) {
+        // shifts of 14 and 15 never clock the LFSR
+        if self.register >> 4 >= 14 {
+            return;
+        }
+        let mut cycles = cycles as u32;
+        while cycles > 0 {
+            let step = cycles.min(self.timer.max(1));
+            self.timer = self.timer.saturating_sub(step);
+            cycles -= step;
+            if self.timer == 0 {
+                self.timer = self.period();
+                self.step_lfsr();
+            }
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // registers are numbered from NR40, which doesn't exist
+    pub(super) fn read_register(&self, register: u8) -> u8 {
+        match register {
+            2 => self.envelope.read(),
+            3 => self.register,
+            4 => 0xBF | if self.length.is_enabled() { 0x40 } else { 0x00 },
+            _ => 0xFF,
+        }
+    }
+
+    // length_step_next tells whether the frame sequencer's next step clocks length counters
+    pub(super) fn write_register(&mut self, register: u8, value: u8, length_step_next: bool) {
+        match register {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.is_dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.register = value,
+            4 => {
+                let trigger = value & 0x80 != 0;
+                if self.length.write_control(value, trigger, length_step_next) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.envelope.is_dac_enabled();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.register);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.register = state.read_u8()?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.timer = state.read_u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+
+    /* ----- PRIVATE ----- */
+    fn period(&self) -> u32 {
+        DIVISORS[(self.register & 0x07) as usize] << (self.register >> 4)
+    }
+
+    // bit 0 XOR bit 1 is shifted in at bit 14, and also at bit 6 in 7-bit mode
+    fn step_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.register & NR43_WIDTH != 0 {
+            self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(nr43: u8, steps: usize) -> Vec<u8> {
+        let mut noise = Noise::new();
+        noise.write_register(2, 0xF0, false);
+        noise.write_register(3, nr43, false);
+        noise.write_register(4, 0x80, false);
+        let period = noise.period() as u16;
+        (0..steps).map(|_| {
+            noise.tick(period);
+            noise.output()
+        }).collect()
+    }
+
+    #[test]
+    fn test_lfsr() {
+        // all ones shift in zeros first
+        assert_eq!(sequence(0x00, 3), [0, 0, 0]);
+        assert_eq!(sequence(0x00, 32767).iter().filter(|&&sample| sample == 15).count(), 16383);
+
+        // 7-bit mode repeats every 127 steps
+        let short = sequence(0x08, 254);
+        assert_eq!(&short[..127], &short[127..]);
+        assert_ne!(&short[..127], &sequence(0x00, 127)[..]);
+    }
+
+    #[test]
+    fn test_clocking() {
+        let mut noise = Noise::new();
+        noise.write_register(2, 0x10, false);
+        noise.write_register(3, 0x21, false);
+        noise.write_register(4, 0x80, false);
+        assert_eq!(noise.period(), 64);
+        noise.tick(63);
+        assert_eq!(noise.lfsr, 0x7FFF);
+        noise.tick(1);
+        assert_eq!(noise.lfsr, 0x3FFF);
+
+        // the two highest shifts stop it
+        noise.write_register(3, 0xE0, false);
+        noise.tick(0xFFFF);
+        assert_eq!(noise.lfsr, 0x3FFF);
+        assert_eq!(noise.read_register(3), 0xE0);
+        assert_eq!(noise.read_register(1), 0xFF);
+    }
+}
@@ -0,0 +1,215 @@
+use std::f64::consts::PI;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const KERNEL_WIDTH: usize = 16;     // output samples each step is spread over
+const KERNEL_PHASES: usize = 64;    // sub-sample positions the kernel is tabulated for
+const CUTOFF: f64 = 0.45;           // of the output rate, just under Nyquist
+
+const BUFFERED_SECONDS: usize = 1;  // samples nobody pulled are dropped beyond this
+
+// the output capacitor's charge factor per clock cycle
+const DMG_CHARGE: f64 = 0.999958;
+const CGB_CHARGE: f64 = 0.998943;
+
+/* ----- TYPE DECLARATIONS ----- */
+// turns a signal that changes at clock cycle boundaries into samples at the host rate by
+// adding a band-limited step for every change, the way a blip buffer does
+struct Resampler {
+    ratio: f64,         // output samples per clock cycle
+    time: f64,          // output position of the current clock cycle
+    buffer: Vec<f32>,   // band-limited deltas not yet integrated
+    sum: f32,           // integral of everything read so far
+}
+
+// stereo samples at the host rate, high-pass filtered like the output capacitors do
+pub(super) struct SampleOutput {
+    sample_rate: u32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    resamplers: [Resampler; 2],
+    level: [f32; 2],        // mixer output as last seen
+    capacitors: [f32; 2],
+    charge: f32,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Resampler {
+    fn new(ratio: f64) -> Resampler {
+        Resampler {
+            ratio,
+            time: 0.0,
+            buffer: vec![0.0; KERNEL_WIDTH],
+            sum: 0.0,
+        }
+    }
+
+    // output samples no future change can alter any more
+    fn available(&self) -> usize {
+        self.time as usize
+    }
+
+    fn add_delta(&mut self, kernel: &[[f32; KERNEL_WIDTH]], delta: f32) {
+        let index = self.time as usize;
+        let phase = ((self.time - index as f64) * KERNEL_PHASES as f64).round() as usize;
+        for (sample, tap) in self.buffer[index..].iter_mut().zip(kernel[phase].iter()) {
+            *sample += delta * tap;
+        }
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        self.time += cycles as f64 * self.ratio;
+        let len = self.time as usize + KERNEL_WIDTH + 1;
+        if self.buffer.len() < len {
+            self.buffer.resize(len, 0.0);
+        }
+    }
+
+    // integrates the next count samples out of the buffer
+    fn take(&mut self, count: usize) -> impl Iterator<Item = f32> + '_ {
+        self.time -= count as f64;
+        let sum = &mut self.sum;
+        self.buffer.drain(..count).map(move |delta| {
+            *sum += delta;
+            *sum
+        })
+    }
+}
+
+impl SampleOutput {
+    pub(super) fn new(clock_rate: u32, sample_rate: u32, cgb: bool) -> SampleOutput {
+        let ratio = sample_rate as f64 / clock_rate as f64;
+        let charge = if cgb { CGB_CHARGE } else { DMG_CHARGE };
+        SampleOutput {
+            sample_rate,
+            kernel: step_kernel(),
+            resamplers: [Resampler::new(ratio), Resampler::new(ratio)],
+            level: [0.0; 2],
+            capacitors: [0.0; 2],
+            charge: charge.powf(1.0 / ratio) as f32,
+        }
+    }
+
+    pub(super) fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // stereo frames ready to be read
+    pub(super) fn available(&self) -> usize {
+        self.resamplers[0].available()
+    }
+
+    // the mixer's left and right output at the current clock cycle
+    pub(super) fn set_level(&mut self, level: [f32; 2]) {
+        let sides = self.resamplers.iter_mut().zip(self.level.iter_mut()).zip(level);
+        for ((resampler, old), new) in sides {
+            if new != *old {
+                resampler.add_delta(&self.kernel, new - *old);
+                *old = new;
+            }
+        }
+    }
+
+    pub(super) fn advance(&mut self, cycles: u32) {
+        for resampler in self.resamplers.iter_mut() {
+            resampler.advance(cycles);
+        }
+
+        let limit = self.sample_rate as usize * BUFFERED_SECONDS;
+        if self.available() > limit {
+            let excess = self.available() - limit;
+            for resampler in self.resamplers.iter_mut() {
+                resampler.take(excess).for_each(drop);
+            }
+        }
+    }
+
+    // fills out with interleaved left and right samples, returns the frames written
+    pub(super) fn read(&mut self, out: &mut [f32]) -> usize {
+        let frames = self.available().min(out.len() / 2);
+        for side in 0..2 {
+            let (capacitor, charge) = (&mut self.capacitors[side], self.charge);
+            let samples = self.resamplers[side].take(frames);
+            for (frame, input) in samples.enumerate() {
+                let filtered = input - *capacitor;
+                *capacitor = input - filtered * charge;
+                out[frame * 2 + side] = filtered.clamp(-1.0, 1.0);
+            }
+        }
+        frames
+    }
+}
+
+/* ----- PRIVATE ----- */
+// a Blackman windowed sinc for each sub-sample phase, normalized so every step adds up to
+// exactly its delta, centred half the kernel width after the step
+fn step_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    (0..=KERNEL_PHASES).map(|phase| {
+        let offset = phase as f64 / KERNEL_PHASES as f64;
+        let mut taps = [0.0; KERNEL_WIDTH];
+        for (k, tap) in taps.iter_mut().enumerate() {
+            let x = k as f64 - offset - (KERNEL_WIDTH / 2) as f64;
+            let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x) };
+            let w = (x + (KERNEL_WIDTH / 2) as f64 + 1.0) / (KERNEL_WIDTH + 1) as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+            *tap = sinc * window;
+        }
+        let total: f64 = taps.iter().sum();
+        taps.map(|tap| (tap / total) as f32)
+    }).collect()
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel() {
+        for taps in step_kernel() {
+            let total: f32 = taps.iter().sum();
+            assert!((total - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        // a step settles to its level after the kernel's delay, without the filter's droop
+        let mut output = SampleOutput::new(4_194_304, 48_000, false);
+        output.charge = 1.0;
+        output.advance(1000);
+        output.set_level([0.5, -0.25]);
+        output.advance(4_194_304 / 100);
+        assert_eq!(output.available(), 491);
+
+        let mut samples = vec![0.0; 1000];
+        assert_eq!(output.read(&mut samples), 491);
+        assert_eq!(output.available(), 0);
+        assert_eq!(samples[0], 0.0);
+        assert!((samples[2 * 100] - 0.5).abs() < 0.01);
+        assert!((samples[2 * 100 + 1] + 0.25).abs() < 0.01);
+
+        // the steps in between ring only a little
+        let peak = samples[..960].iter().step_by(2).cloned().fold(0.0, f32::max);
+        assert!(peak < 0.6);
+    }
+
+    #[test]
+    fn test_high_pass() {
+        let mut output = SampleOutput::new(4_194_304, 44_100, false);
+        output.set_level([1.0, 1.0]);
+        output.advance(4_194_304);
+        let mut samples = vec![0.0; 2 * 44_100];
+        output.read(&mut samples);
+        assert!(samples[2 * 20] > 0.9);
+        assert!(samples[2 * 44_000].abs() < 0.01);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut output = SampleOutput::new(4_194_304, 48_000, false);
+        for _ in 0..10 {
+            output.advance(4_194_304);
+        }
+        assert_eq!(output.available(), 48_000);
+        assert!(output.resamplers[0].buffer.len() < 48_000 + 2 * KERNEL_WIDTH);
+    }
+}
@@ -0,0 +1,364 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use super::units::{Envelope, LengthCounter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+// waveforms for 12.5%, 25%, 50% and 75% duty, one bit per step
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const MAX_FREQUENCY: u16 = 2047;
+
+/* ----- TYPE DECLARATIONS ----- */
+// channel 1 bends its frequency up or down over time
+struct Sweep {
+    register: u8,   // NR10 as written
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    negated: bool,  // a subtraction happened since the last trigger
+}
+
+// square wave channels 1 and 2, only channel 1 has the sweep unit
+pub(super) struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u16,         // clock cycles until the next duty step
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            register: 0,
+            timer: 0,
+            shadow: 0,
+            enabled: false,
+            negated: false,
+        }
+    }
+
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    // a period of 0 reloads the timer with 8
+    fn reload(&mut self) {
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.register & 0x08 != 0 {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+impl Pulse {
+    pub(super) fn new(sweep: bool) -> Pulse {
+        Pulse {
+            enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn is_dac_enabled(&self) -> bool {
+        self.envelope.is_dac_enabled()
+    }
+
+    // every register cleared, keep_length leaves the length counter as it is
+    pub(super) fn power_off(&mut self, keep_length: bool) {
+        *self = Pulse {
+            length: self.length.power_off(keep_length),
+            ..Pulse::new(self.sweep.is_some())
+        };
+    }
+
+    pub(super) fn get_frequency(&self) -> u16 {
+        self.frequency
+    }
+
+    // one duty cycle every eight steps
+    pub(super) fn get_hz(&self) -> f32 {
+        131_072.0 / (2048 - self.frequency) as f32
+    }
+
+    pub(super) fn get_duty(&self) -> u8 {
+        self.duty
+    }
+
+    pub(super) fn get_volume(&self) -> u8 {
+        self.envelope.get_volume()
+    }
+
+    // digital output 0-15, silent while the channel is off
+    pub(super) fn output(&self) -> u8 {
+        if self.enabled && DUTY_PATTERNS[self.duty as usize] >> self.duty_step & 0x01 != 0 {
+            self.get_volume()
+        } else {
+            0
+        }
+    }
+
+    pub(super) fn tick(&mut self, mut cycles: u16) {
+        while cycles > 0 {
+            let step = cycles.min(self.timer.max(1));
+            self.timer = self.timer.saturating_sub(step);
+            cycles -= step;
+            if self.timer == 0 {
+                self.timer = self.period();
+                self.duty_step = (self.duty_step + 1) & 0x07;
+            }
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let frequency = match self.sweep.as_mut() {
+            Some(sweep) => {
+                sweep.timer = sweep.timer.saturating_sub(1);
+                if sweep.timer != 0 {
+                    return;
+                }
+                sweep.reload();
+                if !sweep.enabled || sweep.period() == 0 {
+                    return;
+                }
+                let frequency = sweep.calculate();
+                if frequency > MAX_FREQUENCY {
+                    self.enabled = false;
+                    return;
+                }
+                if sweep.shift() == 0 {
+                    return;
+                }
+                sweep.shadow = frequency;
+                // the new frequency is checked for overflow again right away
+                if sweep.calculate() > MAX_FREQUENCY {
+                    self.enabled = false;
+                }
+                frequency
+            },
+            None => return,
+        };
+        self.frequency = frequency;
+    }
+
+    // registers are numbered from NRx0, with NR20 unused
+    pub(super) fn read_register(&self, register: u8) -> u8 {
+        match register {
+            0 => match &self.sweep {
+                Some(sweep) => 0x80 | sweep.register,
+                None => 0xFF,
+            },
+            1 => 0x3F | (self.duty << 6),
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            4 => 0xBF | if self.length.is_enabled() { 0x40 } else { 0x00 },
+            _ => 0xFF,
+        }
+    }
+
+    // length_step_next tells whether the frame sequencer's next step clocks length counters
+    pub(super) fn write_register(&mut self, register: u8, value: u8, length_step_next: bool) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.register = value & 0x7F;
+                    // leaving subtraction mode after a subtraction was used turns the channel off
+                    if value & 0x08 == 0 && sweep.negated {
+                        self.enabled = false;
+                    }
+                }
+            },
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            },
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.is_dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if self.length.write_control(value, trigger, length_step_next) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_step);
+        state.write_u16(self.frequency);
+        state.write_u16(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        if let Some(sweep) = &self.sweep {
+            state.write_u8(sweep.register);
+            state.write_u8(sweep.timer);
+            state.write_u16(sweep.shadow);
+            state.write_bool(sweep.enabled);
+            state.write_bool(sweep.negated);
+        }
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.duty = state.read_u8()? & 0x03;
+        self.duty_step = state.read_u8()? & 0x07;
+        self.frequency = state.read_u16()? & MAX_FREQUENCY;
+        self.timer = state.read_u16()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.register = state.read_u8()?;
+            sweep.timer = state.read_u8()?;
+            sweep.shadow = state.read_u16()?;
+            sweep.enabled = state.read_bool()?;
+            sweep.negated = state.read_bool()?;
+        }
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    // clock cycles per duty step
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.is_dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = frequency;
+            sweep.negated = false;
+            sweep.reload();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            if sweep.shift() != 0 && sweep.calculate() > MAX_FREQUENCY {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duty() {
+        let mut pulse = Pulse::new(false);
+        pulse.write_register(1, 0x80, false);
+        pulse.write_register(2, 0xF0, false);
+        pulse.write_register(3, 0xFF, false);
+        pulse.write_register(4, 0x87, false);
+        assert!(pulse.is_enabled());
+
+        // frequency 2047 steps every 4 cycles through 10000111
+        let mut wave = Vec::new();
+        for _ in 0..8 {
+            pulse.tick(4);
+            wave.push(pulse.output());
+        }
+        assert_eq!(wave, [15, 15, 0, 0, 0, 0, 15, 15]);
+
+        // turning the DAC off silences the channel
+        pulse.write_register(2, 0x00, false);
+        assert!(!pulse.is_enabled());
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut pulse = Pulse::new(true);
+        pulse.write_register(0, 0x11, false);
+        pulse.write_register(2, 0xF0, false);
+        pulse.write_register(3, 0x00, false);
+        pulse.write_register(4, 0x83, false);
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 0x480);
+        assert!(pulse.is_enabled());
+
+        // 0x6C0 + 0x360 overflows on the check that follows the update
+        pulse.clock_sweep();
+        assert_eq!(pulse.frequency, 0x6C0);
+        assert!(!pulse.is_enabled());
+
+        // the overflow check at trigger time switches it off immediately
+        pulse.write_register(0, 0x01, false);
+        pulse.write_register(3, 0xFF, false);
+        pulse.write_register(4, 0x87, false);
+        assert!(!pulse.is_enabled());
+
+        // clearing negate after a subtraction kills the channel
+        pulse.write_register(0, 0x19, false);
+        pulse.write_register(4, 0x84, false);
+        assert!(pulse.is_enabled());
+        pulse.write_register(0, 0x11, false);
+        assert!(!pulse.is_enabled());
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut pulse = Pulse::new(true);
+        pulse.write_register(0, 0xFF, false);
+        pulse.write_register(1, 0xFF, false);
+        pulse.write_register(3, 0xFF, false);
+        pulse.write_register(4, 0x40, false);
+        assert_eq!(pulse.read_register(0), 0xFF);
+        assert_eq!(pulse.read_register(1), 0xFF);
+        assert_eq!(pulse.read_register(3), 0xFF);
+        assert_eq!(pulse.read_register(4), 0xFF);
+        pulse.write_register(0, 0x00, false);
+        pulse.write_register(1, 0x00, false);
+        pulse.write_register(4, 0x00, false);
+        assert_eq!(pulse.read_register(0), 0x80);
+        assert_eq!(pulse.read_register(1), 0x3F);
+        assert_eq!(pulse.read_register(4), 0xBF);
+        assert_eq!(Pulse::new(false).read_register(0), 0xFF);
+    }
+}
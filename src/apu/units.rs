@@ -0,0 +1,201 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* ----- TYPE DECLARATIONS ----- */
+// silences a channel after a programmable time, clocked at 256 Hz
+pub(super) struct LengthCounter {
+    max: u16,       // 64, or 256 for the wave channel
+    counter: u16,
+    enabled: bool,
+}
+
+// volume that steps up or down at a programmable rate, clocked at 64 Hz
+pub(super) struct Envelope {
+    register: u8,   // NRx2 as written
+    volume: u8,
+    timer: u8,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl LengthCounter {
+    pub(super) fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // the counter a powered off APU is left with, some models keep it
+    pub(super) fn power_off(&self, keep_counter: bool) -> LengthCounter {
+        LengthCounter {
+            max: self.max,
+            counter: if keep_counter { self.counter } else { 0 },
+            enabled: false,
+        }
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_bool(self.enabled);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?.min(self.max);
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+
+    // NRx1 holds the length as max minus the counter
+    pub(super) fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    // returns true when the channel should be switched off
+    pub(super) fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    // NRx4 write: enabling the counter while the sequencer's next step doesn't clock it
+    // clocks it once more, returns true when that switches the channel off
+    pub(super) fn write_control(&mut self, value: u8, trigger: bool, length_step_next: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = value & 0x40 != 0;
+        let mut expired = false;
+        if !was_enabled && self.enabled && !length_step_next {
+            expired = self.clock() && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && !length_step_next {
+                self.counter -= 1;
+            }
+        }
+        expired
+    }
+}
+
+impl Envelope {
+    pub(super) fn new() -> Envelope {
+        Envelope {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub(super) fn read(&self) -> u8 {
+        self.register
+    }
+
+    pub(super) fn write(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    // the DAC is off when the initial volume is 0 and the envelope decreases
+    pub(super) fn is_dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    pub(super) fn get_volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub(super) fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    pub(super) fn clock(&mut self) {
+        if self.register & 0x07 == 0 || self.timer == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.register & 0x08 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.volume = state.read_u8()? & 0x0F;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length() {
+        let mut length = LengthCounter::new(64);
+        length.load(62);
+        length.write_control(0x40, false, true);
+        assert!(!length.clock());
+        assert!(length.clock());
+        assert!(!length.clock());
+
+        // enabling it between length steps clocks it right away
+        let mut length = LengthCounter::new(64);
+        length.load(63);
+        assert!(length.write_control(0x40, false, false));
+
+        // triggering with an expired counter reloads it, one short between length steps
+        let mut length = LengthCounter::new(256);
+        length.write_control(0xC0, true, true);
+        assert_eq!(length.counter, 256);
+        length.write_control(0x00, false, true);
+        length.counter = 0;
+        length.write_control(0xC0, true, false);
+        assert_eq!(length.counter, 255);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut envelope = Envelope::new();
+        envelope.write(0xA2);
+        envelope.trigger();
+        assert_eq!(envelope.get_volume(), 10);
+        envelope.clock();
+        assert_eq!(envelope.get_volume(), 10);
+        envelope.clock();
+        assert_eq!(envelope.get_volume(), 9);
+
+        envelope.write(0xF9);
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.get_volume(), 15);
+
+        envelope.write(0x08);
+        assert!(envelope.is_dac_enabled());
+        envelope.write(0x07);
+        assert!(!envelope.is_dac_enabled());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+use super::units::LengthCounter;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const WAVE_RAM_SIZE: usize = 16;    // 32 4-bit samples
+const TRIGGER_DELAY: u16 = 6;       // clock cycles before the first sample is fetched
+
+/* ----- TYPE DECLARATIONS ----- */
+// channel 3 plays back the samples in wave RAM
+pub(super) struct Wave {
+    model: Model,
+    enabled: bool,
+    dac_enabled: bool,
+    volume: u8,         // NR32 bits 5-6
+    frequency: u16,
+    timer: u16,         // clock cycles until the next sample
+    position: u8,       // sample being played, 0-31
+    sample_byte: u8,    // wave RAM byte the position was last read from
+    ram: [u8; WAVE_RAM_SIZE],
+    length: LengthCounter,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Wave {
+    pub(super) fn new(model: Model) -> Wave {
+        Wave {
+            model,
+            enabled: false,
+            dac_enabled: false,
+            volume: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_byte: 0,
+            ram: [0; WAVE_RAM_SIZE],
+            length: LengthCounter::new(256),
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn is_dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub(super) fn get_frequency(&self) -> u16 {
+        self.frequency
+    }
+
+    // once through all 32 samples
+    pub(super) fn get_hz(&self) -> f32 {
+        65_536.0 / (2048 - self.frequency) as f32
+    }
+
+    // the volume shift as a 0-15 volume like the other channels'
+    pub(super) fn get_volume(&self) -> u8 {
+        match self.volume {
+            0 => 0,
+            code => 15 >> (code - 1),
+        }
+    }
+
+    // every register cleared, wave RAM survives and keep_length leaves the length counter
+    pub(super) fn power_off(&mut self, keep_length: bool) {
+        *self = Wave {
+            length: self.length.power_off(keep_length),
+            ram: self.ram,
+            ..Wave::new(self.model)
+        };
+    }
+
+    // digital output 0-15 after the volume shift, silent while the channel is off
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled || self.volume == 0 {
+            return 0;
+        }
+        let sample = if self.position & 0x01 == 0 { self.sample_byte >> 4 } else { self.sample_byte & 0x0F };
+        sample >> (self.volume - 1)
+    }
+
+    pub(super) fn tick(&mut self, mut cycles: u16) {
+        while cycles > 0 {
+            let step = cycles.min(self.timer.max(1));
+            self.timer = self.timer.saturating_sub(step);
+            cycles -= step;
+            if self.timer == 0 {
+                self.timer = self.period();
+                if self.enabled {
+                    self.position = (self.position + 1) & 0x1F;
+                    self.sample_byte = self.ram[self.position as usize / 2];
+                }
+            }
+        }
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // registers are numbered from NR30
+    pub(super) fn read_register(&self, register: u8) -> u8 {
+        match register {
+            0 => 0x7F | if self.dac_enabled { 0x80 } else { 0x00 },
+            2 => 0x9F | (self.volume << 5),
+            4 => 0xBF | if self.length.is_enabled() { 0x40 } else { 0x00 },
+            _ => 0xFF,
+        }
+    }
+
+    // length_step_next tells whether the frame sequencer's next step clocks length counters
+    pub(super) fn write_register(&mut self, register: u8, value: u8, length_step_next: bool) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.load(value),
+            2 => self.volume = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if self.length.write_control(value, trigger, length_step_next) {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // while the channel plays, the CPU sees the byte the channel is reading
+    pub(super) fn read_ram(&self, index: usize) -> u8 {
+        match self.playing_index() {
+            Some(Some(playing)) => self.ram[playing],
+            Some(None) => 0xFF,
+            None => self.ram[index],
+        }
+    }
+
+    pub(super) fn write_ram(&mut self, index: usize, value: u8) {
+        match self.playing_index() {
+            Some(Some(playing)) => self.ram[playing] = value,
+            Some(None) => {},
+            None => self.ram[index] = value,
+        }
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.volume);
+        state.write_u16(self.frequency);
+        state.write_u16(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample_byte);
+        state.write_bytes(&self.ram);
+        self.length.save_state(state);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.volume = state.read_u8()? & 0x03;
+        self.frequency = state.read_u16()? & 0x07FF;
+        self.timer = state.read_u16()?;
+        self.position = state.read_u8()? & 0x1F;
+        self.sample_byte = state.read_u8()?;
+        state.read_bytes(&mut self.ram)?;
+        self.length.load_state(state)
+    }
+
+    /* ----- PRIVATE ----- */
+    // clock cycles per sample
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    // None while the CPU has wave RAM to itself, otherwise the byte it is redirected to,
+    // or None inside when the DMG locks it out
+    fn playing_index(&self) -> Option<Option<usize>> {
+        if !self.enabled {
+            return None;
+        }
+        if self.model.locks_wave_ram() && self.timer != self.period() {
+            return Some(None);
+        }
+        Some(Some(self.position as usize / 2))
+    }
+
+    fn trigger(&mut self) {
+        // the DMG rewrites the start of wave RAM when retriggered just as a sample is fetched
+        if self.model.has_wave_ram_corruption() && self.enabled && self.timer == 2 {
+            let index = ((self.position + 1) & 0x1F) as usize / 2;
+            if index < 4 {
+                self.ram[0] = self.ram[index];
+            } else {
+                let aligned = index & !0x03;
+                self.ram.copy_within(aligned..aligned + 4, 0);
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.position = 0;
+        self.timer = self.period() + TRIGGER_DELAY;
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(model: Model, frequency: u16) -> Wave {
+        let mut wave = Wave::new(model);
+        for index in 0..WAVE_RAM_SIZE {
+            wave.write_ram(index, (index as u8) << 4 | 0x0F);
+        }
+        wave.write_register(0, 0x80, false);
+        wave.write_register(2, 0x20, false);
+        wave.write_register(3, frequency as u8, false);
+        wave.write_register(4, 0x80 | (frequency >> 8) as u8, false);
+        wave
+    }
+
+    #[test]
+    fn test_playback() {
+        let mut wave = playing(Model::Cgb, 0x7FF);
+        assert!(wave.is_enabled());
+
+        // the first sample comes after the trigger delay, starting at position 1
+        wave.tick(TRIGGER_DELAY + 2);
+        assert_eq!(wave.output(), 0x0F);
+        wave.tick(2);
+        assert_eq!(wave.output(), 0x01);
+
+        // volume codes shift the sample right
+        wave.write_register(2, 0x40, false);
+        assert_eq!(wave.output(), 0x00);
+        wave.tick(2);
+        assert_eq!(wave.output(), 0x07);
+        wave.write_register(2, 0x00, false);
+        assert_eq!(wave.output(), 0x00);
+
+        // the CGB redirects wave RAM accesses to the byte being played
+        assert_eq!(wave.read_ram(9), 0x1F);
+
+        wave.write_register(0, 0x00, false);
+        assert!(!wave.is_enabled());
+        assert_eq!(wave.read_ram(9), 0x9F);
+    }
+
+    #[test]
+    fn test_dmg_lockout() {
+        let mut wave = playing(Model::Dmg, 0x700);
+        wave.tick(TRIGGER_DELAY + 0x100);
+        assert_eq!(wave.read_ram(5), 0xFF);
+        wave.write_ram(5, 0x00);
+        wave.write_register(0, 0x00, false);
+        assert_eq!(wave.read_ram(5), 0x5F);
+    }
+
+    #[test]
+    fn test_trigger_corruption() {
+        // retrigger two cycles before the fetch of byte 4
+        let mut wave = playing(Model::Dmg, 0x7FC);
+        wave.tick(TRIGGER_DELAY + 8 * 8 - 2);
+        wave.write_register(4, 0x87, false);
+        wave.write_register(0, 0x00, false);
+        let ram: Vec<u8> = (0..WAVE_RAM_SIZE).map(|index| wave.read_ram(index)).collect();
+        assert_eq!(&ram[..5], &[0x4F, 0x5F, 0x6F, 0x7F, 0x4F]);
+
+        // within the first four bytes only byte 0 is overwritten
+        let mut wave = playing(Model::Dmg, 0x7FC);
+        wave.tick(TRIGGER_DELAY + 8 * 2 - 2);
+        wave.write_register(4, 0x87, false);
+        wave.write_register(0, 0x00, false);
+        assert_eq!(wave.read_ram(0), 0x1F);
+        assert_eq!(wave.read_ram(1), 0x1F);
+
+        // the CGB leaves wave RAM alone
+        let mut wave = playing(Model::Cgb, 0x7FC);
+        wave.tick(TRIGGER_DELAY + 8 * 8 - 2);
+        wave.write_register(4, 0x87, false);
+        wave.write_register(0, 0x00, false);
+        assert_eq!(wave.read_ram(0), 0x0F);
+    }
+}
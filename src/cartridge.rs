@@ -0,0 +1,52 @@
+/* ----- CONSTANT DECLARATIONS ----- */
+const HEADER_END: usize = 0x0150;
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+const SGB_FLAG_ADDRESS: usize = 0x0146;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const ROM_SIZE_ADDRESS: usize = 0x0148;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+
+/* ----- TYPE DECLARATIONS ----- */
+// the fields of the cartridge header (0x0100-0x014F) the emulator cares about
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl CartridgeHeader {
+    // returns None if the image is too short to contain a header
+    pub fn parse(rom: &[u8]) -> Option<CartridgeHeader> {
+        if rom.len() < HEADER_END {
+            return None;
+        }
+
+        // CGB titles reuse the last title byte for the CGB flag
+        let title = rom[TITLE_START..TITLE_END]
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| b as char)
+            .collect();
+
+        Some(CartridgeHeader {
+            title,
+            cgb_flag: rom[CGB_FLAG_ADDRESS],
+            sgb_flag: rom[SGB_FLAG_ADDRESS],
+            cartridge_type: rom[CARTRIDGE_TYPE_ADDRESS],
+            rom_size: rom[ROM_SIZE_ADDRESS],
+            ram_size: rom[RAM_SIZE_ADDRESS],
+        })
+    }
+
+    // true if the cartridge asks for CGB features (0x80 = CGB enhanced, 0xC0 = CGB only)
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+}
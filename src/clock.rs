@@ -1,11 +1,14 @@
 use std::thread;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct Clock {
     total_cycles: Arc<Mutex<u64>>,
     clock_speed: Arc<u32>,
+    running: Arc<AtomicBool>,
+    generation: Arc<AtomicU64>,     // bumped by stop, so a stopped thread never outlives its run
 }
 
 impl Clock {
@@ -13,13 +16,20 @@ impl Clock {
         Clock {
             total_cycles: Arc::new(Mutex::new(0)), // Initialize total_cycles
             clock_speed: Arc::new(speed),
+            running: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    // start the clock in a separate thread
+    // start the clock in a separate thread, if it isn't running already
     pub fn start(&self) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
         let total_cycles = Arc::clone(&self.total_cycles);
         let clock_speed = Arc::clone(&self.clock_speed);
+        let generation = Arc::clone(&self.generation);
+        let started = generation.load(Ordering::SeqCst);
 
         thread::spawn(move || {
             let period = 1_000_000_000u64 / (*clock_speed as u64); // Convert Hz to nanoseconds
@@ -28,7 +38,7 @@ impl Clock {
             let nanoseconds_per_cycle = Duration::from_nanos(period);
 
             // busy-wait loop to emulate timing
-            loop{
+            while generation.load(Ordering::SeqCst) == started {
                 while Instant::now().duration_since(last_time) < nanoseconds_per_cycle {
                     thread::yield_now();
                 }
@@ -40,6 +50,17 @@ impl Clock {
         });
     }
 
+    // stop counting, the thread exits within a cycle
+    pub fn stop(&self) {
+        if self.running.swap(false, Ordering::SeqCst) {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
     pub fn get_total_cycles(&self) -> u64 {
         let cycles = self.total_cycles.lock().unwrap();
         *cycles
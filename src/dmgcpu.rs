@@ -1,8 +1,15 @@
 use std::fmt;
 #[cfg(feature = "debug")]
 use std::io::{Write};
-use crate::memory::Memory;
+use crate::memory::{self, Memory};
+#[cfg(test)]
+use crate::memory::{INTERRUPT_VBLANK, INTERRUPT_STAT, INTERRUPT_TIMER, INTERRUPT_JOYPAD};
 use crate::clock::Clock;
+use crate::joypad::Button;
+use crate::model::Model;
+use crate::ppu::{Renderer, FRAME_DOTS};
+use crate::serial::SerialDevice;
+use crate::state::{StateError, StateHeader, StateReader, StateWriter};
 use std::thread;
 
 /* ----- CONSTANT DECLARATIONS ----- */
@@ -19,8 +26,12 @@ pub struct DMGCPU {
     memory: Memory,
     halt: bool,
     stop: bool,
+    ime: bool,
+    ime_pending: bool,  // EI takes effect after the following instruction
+    unimplemented: Option<u8>,  // the opcode that stopped the cpu because it isn't emulated yet
     cycle_count: u64,
-    cpu_clock: Clock
+    cpu_clock: Clock,
+    model: Model
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -129,16 +140,207 @@ impl fmt::Debug for FlagRegister {
 
 impl DMGCPU {
     /* ----- PUBLIC ----- */
+    // bare DMG core with cleared registers
     pub fn new(speed: u32) -> DMGCPU {
+        DMGCPU::build(speed, Model::Dmg)
+    }
+
+    // core for the given model, in the state the boot ROM leaves behind
+    pub fn with_model(model: Model) -> DMGCPU {
+        let mut cpu = DMGCPU::build(model.clock_speed(), model);
+        cpu.reset();
+        cpu
+    }
+
+    // core for the model the cartridge header asks for, with the ROM loaded
+    pub fn from_rom(rom: &[u8]) -> DMGCPU {
+        let mut cpu = DMGCPU::with_model(Model::detect(rom));
+        cpu.load_rom(rom);
+        cpu
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        self.memory.load_rom(rom);
+        self.reset();
+    }
+
+    pub fn get_cpu_clock(&self) -> &Clock {
+        &self.cpu_clock
+    }
+
+    pub fn get_cycle_count(&self) -> &u64 {
+        &self.cycle_count
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn get_memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    // the last rendered frame, 160x144 pixels as 0x00RRGGBB
+    pub fn get_framebuffer(&self) -> &[u32] {
+        self.memory.get_ppu().get_framebuffer()
+    }
+
+    pub fn get_frame_count(&self) -> u64 {
+        self.memory.get_ppu().get_frame_count()
+    }
+
+    // apply the CGB LCD color response curve to RGB555 colors
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.memory.get_ppu_mut().set_color_correction(enabled);
+    }
+
+    pub fn is_color_correction_enabled(&self) -> bool {
+        self.memory.get_ppu().is_color_correction_enabled()
+    }
+
+    pub fn get_renderer(&self) -> Renderer {
+        self.memory.get_ppu().get_renderer()
+    }
+
+    // pick the scanline renderer for speed or the pixel FIFO for mid-line accuracy
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.memory.get_ppu_mut().set_renderer(renderer);
+    }
+
+    // held buttons as a mask of Button bits, replacing whatever was held before
+    pub fn set_buttons(&mut self, pressed: u8) {
+        self.memory.set_buttons(pressed);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.memory.set_buttons(self.memory.get_buttons() | button as u8);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.memory.set_buttons(self.memory.get_buttons() & !(button as u8));
+    }
+
+    // plug a link partner into the serial port, returning the one it replaces
+    pub fn attach_serial(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.memory.get_serial_mut().attach(device)
+    }
+
+    pub fn detach_serial(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.memory.get_serial_mut().detach()
+    }
+
+    // snapshot of the whole machine, tagged with the model and the cartridge it was made with
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_header(&StateHeader::new(self.model, self.memory.get_cartridge_header()));
+        state.section(b"CPU ", |state| {
+            state.write_u16(self.registers.af());
+            state.write_u16(self.registers.bc());
+            state.write_u16(self.registers.de());
+            state.write_u16(self.registers.hl());
+            state.write_u16(self.pc);
+            state.write_u16(self.sp);
+            state.write_bool(self.halt);
+            state.write_bool(self.stop);
+            state.write_bool(self.ime);
+            state.write_bool(self.ime_pending);
+            state.write_u64(self.cycle_count);
+        });
+        self.memory.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // restore a snapshot from save_state; it must match this core's model and loaded ROM
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let header = state.read_header()?;
+        if header.model != self.model {
+            return Err(StateError::ModelMismatch { found: header.model, expected: self.model });
+        }
+        if header.cartridge != self.memory.get_cartridge_header() {
+            return Err(StateError::RomMismatch);
+        }
+
+        // a state that turns out to be corrupt part way through puts the machine back as it was
+        let backup = self.save_state();
+        let result = self.load_sections(&mut state);
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup);
+            backup.read_header().unwrap();
+            self.load_sections(&mut backup).unwrap();
+        }
+        result
+    }
+
+    // reset cpu state to what the boot ROM hands over to the cartridge
+    pub fn reset(&mut self) {
+        let boot = self.model.boot_state(self.memory.is_cgb_mode());
+
+        self.registers.write_af(boot.af);
+        self.registers.write_bc(boot.bc);
+        self.registers.write_de(boot.de);
+        self.registers.write_hl(boot.hl);
+        self.sp = boot.sp;
+        self.pc = boot.pc;
+        self.halt = false;
+        self.stop = false;
+        self.ime = false;
+        self.ime_pending = false;
+        self.unimplemented = None;
+        self.memory.boot();
+    }
+
+    // run the cpu in real time until it executes STOP. the clock only paces this loop, the
+    // emulation never reads it, so everything else runs deterministically
+    pub fn run(&mut self) {
+        let clock_start = self.cpu_clock.get_total_cycles();
+        let cycle_start = self.cycle_count;
+        self.cpu_clock.start();
+        while !self.stop {
+            if self.cpu_clock.get_total_cycles() - clock_start > self.cycle_count - cycle_start {
+                self.cycle();
+            }
+            thread::yield_now();
+        }
+        self.cpu_clock.stop();
+    }
+
+    // run as fast as possible until the PPU finishes a frame, or for a frame's worth of cycles
+    // while the LCD is off, stopping early at STOP
+    pub fn run_frame(&mut self) {
+        let frame = self.get_frame_count();
+        let limit = self.cycle_count + ((FRAME_DOTS as u64) << self.memory.is_double_speed() as u32);
+        while self.get_frame_count() == frame && self.cycle_count < limit && !self.stop {
+            self.cycle();
+        }
+    }
+
+    // execute a single instruction or interrupt dispatch, ignoring the clock
+    pub fn step(&mut self) {
+        self.cycle();
+    }
+
+    // whether STOP, or an opcode that isn't emulated, has stopped the cpu
+    pub fn is_stopped(&self) -> bool {
+        self.stop
+    }
+
+    // the opcode the cpu stopped at because it isn't emulated yet
+    pub fn get_unimplemented_opcode(&self) -> Option<u8> {
+        self.unimplemented
+    }
+
+    /* ----- PRIVATE ----- */
+    fn build(speed: u32, model: Model) -> DMGCPU {
         let registers = Registers::new();
-        let mut memory = Memory::new();
+        let memory = Memory::with_model(model);
         let cpu_clock = Clock::new(speed);
         let cycle_count = 0;
 
-        cpu_clock.start();
-
-        memory.write(0xFF00, &[0x76]);
-
         DMGCPU {
             registers,
             pc: 0x0100,
@@ -146,50 +348,102 @@ impl DMGCPU {
             memory,
             halt: false,
             stop: true,
+            ime: false,
+            ime_pending: false,
+            unimplemented: None,
             cycle_count,
-            cpu_clock
+            cpu_clock,
+            model
         }
     }
 
-    pub fn get_cpu_clock(&mut self) -> &Clock {
-        &self.cpu_clock
+    // sections this version doesn't know are skipped, but every one it writes has to be there
+    fn load_sections(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut loaded = Vec::new();
+        while let Some((tag, mut section)) = state.next_section()? {
+            match &tag {
+                b"CPU " => {
+                    self.registers.write_af(section.read_u16()?);
+                    self.registers.write_bc(section.read_u16()?);
+                    self.registers.write_de(section.read_u16()?);
+                    self.registers.write_hl(section.read_u16()?);
+                    self.pc = section.read_u16()?;
+                    self.sp = section.read_u16()?;
+                    self.halt = section.read_bool()?;
+                    self.stop = section.read_bool()?;
+                    self.ime = section.read_bool()?;
+                    self.ime_pending = section.read_bool()?;
+                    self.cycle_count = section.read_u64()?;
+                    self.unimplemented = None;
+                },
+                _ => self.memory.load_section(&tag, &mut section)?,
+            }
+            loaded.push(tag);
+        }
+        if loaded.contains(b"CPU ") && memory::STATE_SECTIONS.iter().all(|&tag| loaded.contains(tag)) {
+            Ok(())
+        } else {
+            Err(StateError::Truncated)
+        }
     }
 
-    pub fn get_cycle_count(&mut self) -> &u64 {
-        &self.cycle_count
+    // run a fetch, decode, execute cycle, or dispatch a pending interrupt
+    fn cycle(&mut self) {
+        let cycles = match self.service_interrupt() {
+            Some(cycles) => cycles,
+            None if self.halt => 4,
+            None => {
+                let enable_interrupts = self.ime_pending;
+                let instr = self.memory.read_byte(self.pc);
+                #[cfg(feature = "debug")]
+                self.cycle_debug();
+                let cycles = self.execute(instr);
+                if enable_interrupts && self.ime_pending {
+                    self.ime = true;
+                    self.ime_pending = false;
+                }
+                cycles
+            }
+        };
+
+        self.cycle_count += cycles as u64;
+        self.memory.tick(cycles);
+
+        // the cpu sits idle while VRAM DMA copies, the rest of the hardware keeps running
+        let mut stall = self.memory.run_vram_dma(self.halt);
+        self.cycle_count += stall as u64;
+        while stall > 0 {
+            let cycles = stall.min(0xFC);
+            self.memory.tick(cycles as u8);
+            stall -= cycles;
+        }
     }
-    // reset cpu state
-    // return true if success, false if fail
-    // pub fn reset(&mut self) -> bool {
-        
-    // }
 
-    // run the cpu
-    pub fn run(&mut self) {
-        
-        while !self.halt {
-            if self.get_cpu_clock().get_total_cycles() > self.cycle_count {
-                self.cycle();
-            }
-            thread::yield_now();
+    // a pending interrupt wakes the cpu from HALT, and is dispatched if IME is set
+    // returns the clock cycles spent on the dispatch
+    fn service_interrupt(&mut self) -> Option<u8> {
+        let pending = self.memory.pending_interrupts();
+        if pending == 0 {
+            return None;
         }
+
+        self.halt = false;
+        if !self.ime {
+            return None;
+        }
+
+        let bit = pending.trailing_zeros() as u16;
+        self.ime = false;
+        self.memory.clear_interrupt(1 << bit);
+        self.push(self.pc);
+        self.pc = 0x0040 + bit * 8;
+        Some(20)
     }
 
-    /* ----- PRIVATE ----- */
-    // run a fetch, decode, execute cycle
-    fn cycle(&mut self) {
-        let instr = self.memory.read_byte(self.pc);
-        #[cfg(feature = "debug")]
-        self.cycle_debug();
-        // self.pc = match self.execute(instr) {
-        //     Some(value) => value,
-        //     None => {
-        //         panic!("Unknown instruction!");
-        //         0
-        //     }
-        // };
-        // let cycles = self.execute(instr);
-        self.cycle_count += self.execute(instr) as u64;
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.memory.write_byte(self.sp, (value & 0xFF) as u8);
+        self.memory.write_byte(self.sp.wrapping_add(1), (value >> 8) as u8);
     }
 
     // TODO make execute return duration instead of new pc
@@ -307,7 +561,10 @@ impl DMGCPU {
                 4
             },
             0x10 => {   //  STOP : 4 clock cycles
-                self.stop = true;
+                // a CGB speed switch resumes execution instead of stopping
+                if !self.memory.speed_switch() {
+                    self.stop = true;
+                }
                 self.pc += 2;
                 4
             },
@@ -378,7 +635,23 @@ impl DMGCPU {
                 self.pc += 1;
                 4
             }
-            2_u8..=u8::MAX => todo!()
+            0xF3 => {   // DI : 4 clock cycles
+                self.ime = false;
+                self.ime_pending = false;
+                self.pc += 1;
+                4
+            },
+            0xFB => {   // EI : 4 clock cycles
+                self.ime_pending = true;
+                self.pc += 1;
+                4
+            },
+            _ => {
+                // stop rather than take the host down, pc stays on the opcode
+                self.unimplemented = Some(instr);
+                self.stop = true;
+                0
+            }
         }
     }
 
@@ -411,6 +684,7 @@ impl DMGCPU {
 
 /* ---------------------------------- TESTS ---------------------------------- */
 #[cfg(test)]
+#[allow(non_snake_case, clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -422,9 +696,9 @@ mod tests {
     
     impl TestDMGCPU {
         fn new() -> Self {
-            let mut cpu = DMGCPU::new(4_190_000);
+            let cpu = DMGCPU::new(4_190_000);
             let initial_pc = cpu.pc;
-            let initial_registers = cpu.registers.clone();
+            let initial_registers = cpu.registers;
             TestDMGCPU {
                 cpu,
                 initial_pc,
@@ -822,4 +1096,210 @@ mod tests {
         assert_eq!(test_cpu.cpu.pc, test_cpu.initial_pc + 1);
         assert_eq!(test_cpu.cpu.halt, true);
     }
+
+    #[test]
+    fn test_with_model() {
+        let cpu = DMGCPU::with_model(Model::Mgb);
+
+        assert_eq!(cpu.get_model(), Model::Mgb);
+        assert_eq!(cpu.registers.a, 0xFF);
+        assert_eq!(cpu.registers.bc(), 0x0013);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.pc, 0x0100);
+    }
+
+    #[test]
+    fn test_from_rom() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        let cpu = DMGCPU::from_rom(&rom);
+        assert_eq!(cpu.get_model(), Model::Cgb);
+        assert!(cpu.memory.is_cgb_mode());
+        assert_eq!(cpu.registers.de(), 0xFF56);
+
+        // same ROM forced onto monochrome hardware
+        let mut cpu = DMGCPU::with_model(Model::Sgb);
+        cpu.load_rom(&rom);
+        assert!(!cpu.memory.is_cgb_mode());
+        assert_eq!(cpu.registers.hl(), 0xC060);
+
+        // DMG-only ROM on CGB hardware runs in compatibility mode
+        rom[0x0143] = 0x00;
+        let mut cpu = DMGCPU::with_model(Model::Cgb);
+        cpu.load_rom(&rom);
+        assert!(!cpu.memory.is_cgb_mode());
+        assert_eq!(cpu.registers.de(), 0x0008);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let mut cpu = DMGCPU::with_model(Model::Dmg);
+        cpu.memory.write_byte(0xFF0F, 0x00);
+        assert_eq!(cpu.memory.read_byte(0xFF00), 0xCF);
+
+        cpu.press(Button::A);
+        cpu.press(Button::Up);
+        assert_eq!(cpu.memory.read_byte(0xFF00), 0xCA);
+        assert_eq!(cpu.memory.read_byte(0xFF0F) & INTERRUPT_JOYPAD, INTERRUPT_JOYPAD);
+
+        cpu.release(Button::A);
+        cpu.memory.write_byte(0xFF00, 0x10);
+        assert_eq!(cpu.memory.read_byte(0xFF00), 0xDF);
+        cpu.set_buttons(Button::Start as u8);
+        assert_eq!(cpu.memory.read_byte(0xFF00), 0xD7);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = 0x80;
+        rom[0x0100] = 0x10;     // STOP
+        rom[0x0102] = 0x10;
+        let mut cpu = DMGCPU::from_rom(&rom);
+
+        // without KEY1 armed STOP really stops
+        cpu.cycle();
+        assert_eq!(cpu.stop, true);
+
+        cpu.stop = false;
+        cpu.memory.write_byte(0xFF4D, 0x01);
+        assert_eq!(cpu.memory.read_byte(0xFF4D), 0x7F);
+        cpu.cycle();
+        assert_eq!(cpu.stop, false);
+        assert!(cpu.memory.is_double_speed());
+        assert_eq!(cpu.memory.read_byte(0xFF4D), 0xFE);
+    }
+
+    #[test]
+    fn test_0xF3() {
+        let mut test_cpu = TestDMGCPU::new();
+        test_cpu.cpu.ime = true;
+        test_cpu.cpu.memory.write(0x0100, &[0xF3]);
+        test_cpu.cycle();
+
+        assert_eq!(test_cpu.cpu.pc, test_cpu.initial_pc + 1);
+        assert_eq!(test_cpu.cpu.ime, false);
+    }
+
+    #[test]
+    fn test_0xFB() {
+        let mut test_cpu = TestDMGCPU::new();
+        test_cpu.cpu.memory.write(0x0100, &[0xFB, 0x00]);
+        test_cpu.cycle();
+
+        // enabled only after the next instruction
+        assert_eq!(test_cpu.cpu.pc, test_cpu.initial_pc + 1);
+        assert_eq!(test_cpu.cpu.ime, false);
+        test_cpu.cycle();
+        assert_eq!(test_cpu.cpu.ime, true);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut test_cpu = TestDMGCPU::new();
+        test_cpu.cpu.sp = 0xFFFE;
+        test_cpu.cpu.ime = true;
+        test_cpu.cpu.memory.write(0xFFFF, &[INTERRUPT_STAT | INTERRUPT_TIMER]);
+        test_cpu.cpu.memory.request_interrupt(INTERRUPT_TIMER | INTERRUPT_STAT);
+        test_cpu.cycle();
+
+        // lowest bit wins, return address is pushed
+        assert_eq!(test_cpu.cpu.pc, 0x0048);
+        assert_eq!(test_cpu.cpu.ime, false);
+        assert_eq!(test_cpu.cpu.memory.read_word(test_cpu.cpu.sp), test_cpu.initial_pc);
+        assert_eq!(test_cpu.cpu.memory.read_byte(0xFF0F) & 0x1F, INTERRUPT_TIMER);
+    }
+
+    #[test]
+    fn test_halt_wakeup() {
+        let mut test_cpu = TestDMGCPU::new();
+        test_cpu.cpu.memory.write(0x0100, &[0x76, 0x00]);
+        test_cpu.cycle();
+        test_cpu.cycle();
+        assert_eq!(test_cpu.cpu.halt, true);
+        assert_eq!(test_cpu.cpu.pc, test_cpu.initial_pc + 1);
+
+        // IME clear: wake up without dispatching
+        test_cpu.cpu.memory.write(0xFFFF, &[INTERRUPT_VBLANK]);
+        test_cpu.cpu.memory.request_interrupt(INTERRUPT_VBLANK);
+        test_cpu.cycle();
+        assert_eq!(test_cpu.cpu.halt, false);
+        assert_eq!(test_cpu.cpu.pc, test_cpu.initial_pc + 2);
+    }
+
+    #[test]
+    fn test_vblank_interrupt() {
+        let mut cpu = DMGCPU::with_model(Model::Dmg);
+        cpu.memory.write(0xFF0F, &[0x00]);
+        for _ in 0..(456 * 144 / 4) {
+            cpu.cycle();
+        }
+        assert_eq!(cpu.memory.read_byte(0xFF44), 144);
+        assert_eq!(cpu.memory.read_byte(0xFF0F) & INTERRUPT_VBLANK, INTERRUPT_VBLANK);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0143] = 0x80;
+        let mut cpu = DMGCPU::from_rom(&rom);
+        cpu.pc = 0x0150;
+        cpu.memory.write_byte(0xFF26, 0x80);
+        cpu.memory.write_byte(0xFF12, 0xF0);
+        cpu.memory.write_byte(0xFF14, 0x87);
+        cpu.memory.write_byte(0xFF70, 0x03);
+        cpu.memory.write_byte(0xD123, 0x42);
+        for _ in 0..5_000 {
+            cpu.cycle();
+        }
+        let state = cpu.save_state();
+
+        // the ROM is NOPs past the header, so the CPU has to stay below 0x8000
+        // running on from a loaded state ends up exactly where running on from the save did
+        for _ in 0..12_000 {
+            cpu.cycle();
+        }
+        let expected = cpu.save_state();
+        let mut restored = DMGCPU::from_rom(&rom);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.memory.read_byte(0xD123), 0x42);
+        for _ in 0..12_000 {
+            restored.cycle();
+        }
+        assert_eq!(restored.save_state(), expected);
+        assert_eq!(restored.get_framebuffer(), cpu.get_framebuffer());
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let rom = vec![0; 0x8000];
+        let mut cpu = DMGCPU::from_rom(&rom);
+        let state = cpu.save_state();
+        let before = cpu.save_state();
+
+        let mut other = rom.clone();
+        other[0x0134] = b'X';
+        let mut wrong_rom = DMGCPU::from_rom(&other);
+        assert_eq!(wrong_rom.load_state(&state), Err(StateError::RomMismatch));
+        let mut wrong_model = DMGCPU::with_model(Model::Mgb);
+        wrong_model.load_rom(&rom);
+        let error = wrong_model.load_state(&state).unwrap_err();
+        assert_eq!(error, StateError::ModelMismatch { found: Model::Dmg, expected: Model::Mgb });
+
+        let mut newer = state.clone();
+        newer[8] = newer[8].wrapping_add(1);
+        assert!(matches!(cpu.load_state(&newer), Err(StateError::Version { .. })));
+
+        // a truncated state leaves the machine untouched
+        assert_eq!(cpu.load_state(&state[..state.len() - 100]), Err(StateError::Truncated));
+        assert_eq!(cpu.save_state(), before);
+
+        // so does one that ends cleanly right after the CPU section
+        cpu.step();
+        let before = cpu.save_state();
+        let end = state.windows(4).position(|tag| tag == b"MEM ").unwrap();
+        assert_eq!(cpu.load_state(&state[..end]), Err(StateError::Truncated));
+        assert_eq!(cpu.save_state(), before);
+    }
 }
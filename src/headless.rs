@@ -0,0 +1,280 @@
+use std::io;
+use std::path::Path;
+use crate::dmgcpu::DMGCPU;
+use crate::serial::SerialCapture;
+use crate::wav::WavWriter;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const DEFAULT_CYCLE_LIMIT: u64 = 4_194_304 * 120;   // two emulated minutes
+
+const AUDIO_SAMPLE_RATE: u32 = 48_000;
+const AUDIO_CHUNK_FRAMES: usize = 4096;     // samples are written out in chunks this big
+
+/* ----- TYPE DECLARATIONS ----- */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    TimedOut,
+    Stopped,    // STOP or an opcode that isn't emulated, before a result was reported
+}
+
+// runs a core as fast as possible with a capture sink on the serial port, until the
+// output reports a result the way blargg's test ROMs do
+pub struct HeadlessRunner {
+    cpu: DMGCPU,
+    capture: SerialCapture,
+    pass_pattern: String,
+    fail_pattern: String,
+    cycle_limit: u64,
+    audio: Option<AudioDump>,
+}
+
+// the APU's mix, and optionally every channel on its own, going into WAV files
+struct AudioDump {
+    mix: WavWriter,
+    stems: Vec<WavWriter>,
+    error: Option<io::Error>,   // the first write that failed, recording stops there
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Outcome {
+    // process exit code for scripts and CI, 2 is left for usage errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Passed => 0,
+            Outcome::Failed => 1,
+            Outcome::TimedOut | Outcome::Stopped => 3,
+        }
+    }
+}
+
+impl HeadlessRunner {
+    pub fn new(mut cpu: DMGCPU) -> HeadlessRunner {
+        let capture = SerialCapture::new();
+        cpu.attach_serial(Box::new(capture.clone()));
+        HeadlessRunner {
+            cpu,
+            capture,
+            pass_pattern: String::from("Passed"),
+            fail_pattern: String::from("Failed"),
+            cycle_limit: DEFAULT_CYCLE_LIMIT,
+            audio: None,
+        }
+    }
+
+    // record the audio of every run to a stereo WAV file, with stems each channel also goes
+    // to a file of its own named after the mix, like song.ch1.wav next to song.wav
+    pub fn record_audio<P: AsRef<Path>>(&mut self, path: P, stems: bool) -> io::Result<()> {
+        let path = path.as_ref();
+        let mix = WavWriter::create(path, AUDIO_SAMPLE_RATE, 2)?;
+        let stems = if stems {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            (1..=4).map(|channel| {
+                WavWriter::create(path.with_file_name(format!("{}.ch{}.wav", stem, channel)), AUDIO_SAMPLE_RATE, 2)
+            }).collect::<io::Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+
+        let apu = self.cpu.get_memory_mut().get_apu_mut();
+        apu.set_sample_rate(AUDIO_SAMPLE_RATE);
+        if !stems.is_empty() {
+            apu.enable_stems();
+        }
+        self.audio = Some(AudioDump { mix, stems, error: None });
+        Ok(())
+    }
+
+    // write out what is left and complete the files, reporting the first error on the way
+    pub fn finish_audio(&mut self) -> io::Result<()> {
+        let Some(mut audio) = self.audio.take() else {
+            return Ok(());
+        };
+        audio.drain(&mut self.cpu, 0);
+        if let Some(error) = audio.error {
+            return Err(error);
+        }
+        audio.mix.finish()?;
+        for stem in audio.stems.iter_mut() {
+            stem.finish()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_patterns(&mut self, pass: &str, fail: &str) {
+        self.pass_pattern = pass.to_string();
+        self.fail_pattern = fail.to_string();
+    }
+
+    // give up after this many cpu cycles
+    pub fn set_cycle_limit(&mut self, cycles: u64) {
+        self.cycle_limit = cycles;
+    }
+
+    pub fn get_cpu(&self) -> &DMGCPU {
+        &self.cpu
+    }
+
+    pub fn get_cpu_mut(&mut self) -> &mut DMGCPU {
+        &mut self.cpu
+    }
+
+    pub fn get_output(&self) -> String {
+        self.capture.get_output()
+    }
+
+    pub fn run(&mut self) -> Outcome {
+        let outcome = self.run_until_result();
+        if let Some(audio) = self.audio.as_mut() {
+            audio.drain(&mut self.cpu, 0);
+        }
+        outcome
+    }
+
+    /* ----- PRIVATE ----- */
+    fn run_until_result(&mut self) -> Outcome {
+        let mut checked = usize::MAX;
+        while *self.cpu.get_cycle_count() < self.cycle_limit {
+            self.cpu.step();
+            if let Some(audio) = self.audio.as_mut() {
+                audio.drain(&mut self.cpu, AUDIO_CHUNK_FRAMES);
+            }
+
+            // only look for the patterns when something new arrived
+            if self.capture.len() != checked {
+                checked = self.capture.len();
+                let output = self.capture.get_output();
+                if output.contains(&self.fail_pattern) {
+                    return Outcome::Failed;
+                }
+                if output.contains(&self.pass_pattern) {
+                    return Outcome::Passed;
+                }
+            }
+            if self.cpu.is_stopped() {
+                return Outcome::Stopped;
+            }
+        }
+        Outcome::TimedOut
+    }
+}
+
+impl AudioDump {
+    // write the samples ready so far, once there are at least min_frames
+    fn drain(&mut self, cpu: &mut DMGCPU, min_frames: usize) {
+        let apu = cpu.get_memory_mut().get_apu_mut();
+        let frames = apu.samples_available();
+        if frames == 0 || frames < min_frames || self.error.is_some() {
+            return;
+        }
+
+        let mut samples = vec![0; frames * 2];
+        let written = apu.read_samples_i16(&mut samples);
+        let mut result = self.mix.write_samples(&samples[..written * 2]);
+        for (channel, stem) in self.stems.iter_mut().enumerate() {
+            let written = apu.read_stem_samples_i16(channel, &mut samples);
+            result = result.and_then(|_| stem.write_samples(&samples[..written * 2]));
+        }
+        if let Err(error) = result {
+            self.error = Some(error);
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a runner on a ROM that halts for good, with the text already sent over serial
+    fn printed(text: &str) -> HeadlessRunner {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x76;
+        let mut runner = HeadlessRunner::new(DMGCPU::from_rom(&rom));
+        let memory = runner.get_cpu_mut().get_memory_mut();
+        for byte in text.bytes() {
+            memory.write_byte(0xFF01, byte);
+            memory.write_byte(0xFF02, 0x81);
+        }
+        runner
+    }
+
+    #[test]
+    fn test_passed() {
+        let mut runner = printed("cpu_instrs\n\nPassed all tests\n");
+        assert_eq!(runner.run(), Outcome::Passed);
+        assert_eq!(runner.get_output(), "cpu_instrs\n\nPassed all tests\n");
+        assert_eq!(Outcome::Passed.exit_code(), 0);
+    }
+
+    #[test]
+    fn test_failed() {
+        // failures win over a pass reported earlier
+        let mut runner = printed("01:Passed 02:Failed #3");
+        assert_eq!(runner.run(), Outcome::Failed);
+        assert_eq!(Outcome::Failed.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_timed_out() {
+        let mut runner = printed("Pass");
+        runner.set_cycle_limit(100_000);
+        assert_eq!(runner.run(), Outcome::TimedOut);
+        assert!(*runner.get_cpu().get_cycle_count() >= 100_000);
+
+        // the output so far is checked against new patterns right away
+        runner.set_patterns("Pa", "Fail");
+        runner.set_cycle_limit(200_000);
+        assert_eq!(runner.run(), Outcome::Passed);
+        assert!(*runner.get_cpu().get_cycle_count() < 200_000);
+    }
+
+    #[test]
+    fn test_stopped() {
+        let mut runner = printed("");
+        runner.get_cpu_mut().get_memory_mut().write(0x0100, &[0x10, 0x00]);
+        assert_eq!(runner.run(), Outcome::Stopped);
+        assert_eq!(runner.get_cpu().get_unimplemented_opcode(), None);
+        assert_eq!(Outcome::Stopped.exit_code(), 3);
+
+        // so does an opcode the cpu can't run yet
+        let mut runner = printed("");
+        runner.get_cpu_mut().get_memory_mut().write(0x0100, &[0xD3]);
+        assert_eq!(runner.run(), Outcome::Stopped);
+        assert_eq!(runner.get_cpu().get_unimplemented_opcode(), Some(0xD3));
+    }
+
+    #[test]
+    fn test_record_audio() {
+        let dir = std::env::temp_dir().join(format!("crabboy_audio_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.wav");
+
+        // a ROM that halts with channel 2 playing
+        let mut runner = printed("Passed");
+        runner.record_audio(&path, true).unwrap();
+        let memory = runner.get_cpu_mut().get_memory_mut();
+        memory.write_byte(0xFF17, 0xF0);
+        memory.write_byte(0xFF19, 0x87);
+        runner.set_patterns("never", "never either");
+        runner.set_cycle_limit(4_194_304 / 10);
+        assert_eq!(runner.run(), Outcome::TimedOut);
+        runner.finish_audio().unwrap();
+
+        let mix = std::fs::read(&path).unwrap();
+        let stems: Vec<Vec<u8>> = (1..=4)
+            .map(|channel| std::fs::read(dir.join(format!("song.ch{}.wav", channel))).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // a tenth of a second of stereo 16-bit samples
+        let size = u32::from_le_bytes(mix[40..44].try_into().unwrap()) as usize;
+        assert_eq!(size, mix.len() - 44);
+        assert!((4790 * 4..=4810 * 4).contains(&size));
+        assert!(stems.iter().all(|stem| stem.len() == mix.len()));
+        assert!(mix[44..].iter().any(|&byte| byte != 0));
+        assert!(stems[1][44..].iter().any(|&byte| byte != 0));
+        assert!(stems[2][44..].iter().all(|&byte| byte == 0));
+    }
+}
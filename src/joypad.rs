@@ -0,0 +1,133 @@
+use crate::memory::INTERRUPT_JOYPAD;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const SELECT_DIRECTIONS: u8 = 0x10;     // P14, active low
+const SELECT_BUTTONS: u8 = 0x20;        // P15, active low
+
+/* ----- TYPE DECLARATIONS ----- */
+// each button is a bit in the masks taken by set_buttons, directions in the low nibble
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Button {
+    Right = 0x01,
+    Left = 0x02,
+    Up = 0x04,
+    Down = 0x08,
+    A = 0x10,
+    B = 0x20,
+    Select = 0x40,
+    Start = 0x80,
+}
+
+pub struct Joypad {
+    select: u8,     // P14/P15 as last written
+    pressed: u8,    // Button bits currently held
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            pressed: 0,
+        }
+    }
+
+    // the boot ROM leaves both button groups selected
+    pub fn boot(&mut self) {
+        self.select = 0;
+    }
+
+    pub fn get_buttons(&self) -> u8 {
+        self.pressed
+    }
+
+    // replace the held buttons, returns the interrupts requested
+    pub fn set_buttons(&mut self, pressed: u8) -> u8 {
+        let lines = self.lines();
+        self.pressed = pressed;
+        self.check_edge(lines)
+    }
+
+    pub fn read_register(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write_register(&mut self, value: u8) -> u8 {
+        let lines = self.lines();
+        self.select = value & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+        self.check_edge(lines)
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.select);
+        state.write_u8(self.pressed);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.select = state.read_u8()?;
+        self.pressed = state.read_u8()?;
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    // P10-P13, a line reads 0 while a button of a selected group holds it down
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0F
+    }
+
+    // any line going from high to low requests the joypad interrupt
+    fn check_edge(&self, old_lines: u8) -> u8 {
+        if old_lines & !self.lines() != 0 { INTERRUPT_JOYPAD } else { 0 }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_lines() {
+        let mut joypad = Joypad::new();
+        assert_eq!(joypad.read_register(), 0xFF);
+        joypad.set_buttons(Button::Start as u8 | Button::Left as u8);
+
+        // nothing is visible until a group is selected
+        assert_eq!(joypad.read_register(), 0xFF);
+        joypad.write_register(0x20);
+        assert_eq!(joypad.read_register(), 0xED);
+        joypad.write_register(0x10);
+        assert_eq!(joypad.read_register(), 0xD7);
+        joypad.write_register(0x00);
+        assert_eq!(joypad.read_register(), 0xC5);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut joypad = Joypad::new();
+        joypad.write_register(0x20);
+        assert_eq!(joypad.set_buttons(Button::Down as u8), INTERRUPT_JOYPAD);
+        // holding or releasing doesn't fire again
+        assert_eq!(joypad.set_buttons(Button::Down as u8), 0);
+        assert_eq!(joypad.set_buttons(0), 0);
+
+        // buttons of the group that isn't selected don't pull any line low
+        assert_eq!(joypad.set_buttons(Button::A as u8), 0);
+        // until the group is selected
+        assert_eq!(joypad.write_register(0x10), INTERRUPT_JOYPAD);
+    }
+}
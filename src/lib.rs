@@ -4,3 +4,17 @@
 pub mod memory;
 pub mod dmgcpu;
 pub mod clock;
+pub mod model;
+pub mod cartridge;
+pub mod ppu;
+pub mod apu;
+pub mod wav;
+pub mod state;
+pub mod rewind;
+pub mod movie;
+pub mod timer;
+pub mod joypad;
+pub mod serial;
+pub mod headless;
+pub mod link;
+pub mod printer;
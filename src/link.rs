@@ -0,0 +1,347 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::rc::Rc;
+use crate::dmgcpu::DMGCPU;
+use crate::serial::SerialDevice;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+// cycles either console may run ahead of the other. a transfer gets back the partner's SB as
+// it was somewhere within a quantum of the start, and a listening partner gets the byte at the
+// end of the quantum, so a game that rewrites SB or gives up on its partner faster than that
+// can behave differently than on hardware
+const QUANTUM_CYCLES: u64 = 1024;
+
+const MESSAGE_SIZE: usize = 10;
+const MESSAGE_SYNC: u8 = 0;         // sender finished the quantum
+const MESSAGE_TRANSFER: u8 = 1;     // sender clocked a byte out
+const MESSAGE_REPLY: u8 = 2;        // byte shifted back for a transfer
+
+/* ----- TYPE DECLARATIONS ----- */
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+// link cable to another crabboy instance over a socket. the consoles don't run in lockstep,
+// they wait for each other at the end of every quantum, see QUANTUM_CYCLES for what that
+// costs in accuracy
+pub struct LinkCable {
+    stream: Box<dyn Stream>,
+    connected: bool,
+    cycle: u64,             // cycles since the cable was plugged in
+    quantum: u64,           // quanta both sides have finished
+    peer_quantum: u64,
+    listening: Option<u8>,  // SB while this console waits on the partner's clock
+    received: Option<u8>,   // partner's byte for the transfer this console is waiting on
+    reply: Option<u8>,      // partner's answer to this console's transfer
+}
+
+// state both ends of a virtual cable see
+#[derive(Default)]
+struct Wire {
+    listening: [Option<u8>; 2],     // SB of each side while it waits on the other's clock
+    received: [Option<u8>; 2],      // byte clocked into each side, picked up on its next poll
+}
+
+// one end of a cable between two consoles in the same process
+pub struct VirtualCable {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+// two consoles joined by a virtual cable, stepped one instruction at a time so that
+// neither gets ahead of the other and every run plays out the same way
+pub struct LinkedConsoles {
+    consoles: [DMGCPU; 2],
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl LinkCable {
+    pub fn listen_tcp<A: ToSocketAddrs>(address: A) -> io::Result<LinkCable> {
+        LinkCable::accept_tcp(&TcpListener::bind(address)?)
+    }
+
+    // wait for the partner on an already bound listener
+    pub fn accept_tcp(listener: &TcpListener) -> io::Result<LinkCable> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    pub fn connect_tcp<A: ToSocketAddrs>(address: A) -> io::Result<LinkCable> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(path: P) -> io::Result<LinkCable> {
+        LinkCable::accept_unix(&UnixListener::bind(path)?)
+    }
+
+    #[cfg(unix)]
+    pub fn accept_unix(listener: &UnixListener) -> io::Result<LinkCable> {
+        let (stream, _) = listener.accept()?;
+        Ok(LinkCable::new(Box::new(stream)))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<LinkCable> {
+        Ok(LinkCable::new(Box::new(UnixStream::connect(path)?)))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /* ----- PRIVATE ----- */
+    fn new(stream: Box<dyn Stream>) -> LinkCable {
+        LinkCable {
+            stream,
+            connected: true,
+            cycle: 0,
+            quantum: 0,
+            peer_quantum: 0,
+            listening: None,
+            received: None,
+            reply: None,
+        }
+    }
+
+    fn send(&mut self, kind: u8, value: u64, byte: u8) {
+        let mut message = [0; MESSAGE_SIZE];
+        message[0] = kind;
+        message[1..9].copy_from_slice(&value.to_le_bytes());
+        message[9] = byte;
+        if self.connected && self.stream.write_all(&message).is_err() {
+            self.connected = false;
+        }
+    }
+
+    // block for the partner's next message and act on it
+    fn receive(&mut self) {
+        let mut message = [0; MESSAGE_SIZE];
+        if !self.connected || self.stream.read_exact(&mut message).is_err() {
+            self.connected = false;
+            return;
+        }
+
+        let value = u64::from_le_bytes(message[1..9].try_into().unwrap());
+        match message[0] {
+            MESSAGE_SYNC => self.peer_quantum = value,
+            MESSAGE_TRANSFER => {
+                // a console that isn't listening leaves the line high
+                let answer = self.listening.unwrap_or(0xFF);
+                if self.listening.take().is_some() {
+                    self.received = Some(message[9]);
+                }
+                self.send(MESSAGE_REPLY, self.cycle, answer);
+            },
+            MESSAGE_REPLY => self.reply = Some(message[9]),
+            _ => self.connected = false,
+        }
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.send(MESSAGE_TRANSFER, self.cycle, outgoing);
+        // the partner answers while it is still inside this quantum, or blocked at its end
+        while self.connected && self.reply.is_none() {
+            self.receive();
+        }
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn external_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        let received = self.received.take();
+        self.listening = if received.is_some() { None } else { Some(outgoing) };
+        received
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycle += cycles as u64;
+        while self.connected && self.cycle >= (self.quantum + 1) * QUANTUM_CYCLES {
+            self.quantum += 1;
+            self.send(MESSAGE_SYNC, self.quantum, 0);
+            while self.connected && self.peer_quantum < self.quantum {
+                self.receive();
+            }
+        }
+        // external_transfer renews this every tick for as long as the console listens
+        self.listening = None;
+    }
+}
+
+impl VirtualCable {
+    pub fn pair() -> (VirtualCable, VirtualCable) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        (VirtualCable { wire: Rc::clone(&wire), side: 0 }, VirtualCable { wire, side: 1 })
+    }
+}
+
+impl SerialDevice for VirtualCable {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        match wire.listening[other].take() {
+            Some(answer) => {
+                wire.received[other] = Some(outgoing);
+                answer
+            },
+            None => 0xFF,
+        }
+    }
+
+    fn external_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let received = wire.received[self.side].take();
+        wire.listening[self.side] = if received.is_some() { None } else { Some(outgoing) };
+        received
+    }
+
+    fn tick(&mut self, _cycles: u8) {
+        // external_transfer renews this every tick for as long as the console listens
+        self.wire.borrow_mut().listening[self.side] = None;
+    }
+}
+
+impl LinkedConsoles {
+    pub fn new(mut first: DMGCPU, mut second: DMGCPU) -> LinkedConsoles {
+        let (a, b) = VirtualCable::pair();
+        first.attach_serial(Box::new(a));
+        second.attach_serial(Box::new(b));
+        LinkedConsoles { consoles: [first, second] }
+    }
+
+    pub fn get(&self, index: usize) -> &DMGCPU {
+        &self.consoles[index]
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut DMGCPU {
+        &mut self.consoles[index]
+    }
+
+    pub fn into_inner(self) -> [DMGCPU; 2] {
+        self.consoles
+    }
+
+    // step whichever console is behind, the first one on ties
+    pub fn step(&mut self) {
+        let behind = if self.consoles[1].get_cycle_count() < self.consoles[0].get_cycle_count() { 1 } else { 0 };
+        self.consoles[behind].step();
+    }
+
+    // run until both consoles have reached the given cycle count
+    pub fn run_until(&mut self, cycles: u64) {
+        while self.consoles.iter().any(|console| *console.get_cycle_count() < cycles) {
+            self.step();
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::serial::Serial;
+    use crate::memory::INTERRUPT_SERIAL;
+
+    // exchange a byte between a console clocking the transfer and one listening,
+    // returning what each side ended up with in SB
+    fn exchange(master: LinkCable, slave: LinkCable) -> (u8, u8) {
+        let slave = thread::spawn(move || run_side(slave, 0x34, 0x80, 0));
+        let master = run_side(master, 0x12, 0x81, 2048);
+        (master, slave.join().unwrap())
+    }
+
+    fn run_side(cable: LinkCable, sb: u8, sc: u8, delay: u32) -> u8 {
+        let mut serial = Serial::new();
+        serial.attach(Box::new(cable));
+        serial.write_register(0xFF01, sb);
+        let mut sb = None;
+        for cycle in (0..32768).step_by(4) {
+            if cycle == delay {
+                serial.write_register(0xFF02, sc);
+            }
+            if serial.tick(4) & INTERRUPT_SERIAL != 0 {
+                sb = Some(serial.read_register(0xFF01));
+            }
+        }
+        sb.unwrap_or(0)
+    }
+
+    fn halting_console() -> DMGCPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x76;
+        DMGCPU::from_rom(&rom)
+    }
+
+    #[test]
+    fn test_linked_consoles() {
+        let mut pair = LinkedConsoles::new(halting_console(), halting_console());
+        for (index, sb, sc) in [(0, 0x12, 0x80), (1, 0x34, 0x81)] {
+            let memory = pair.get_mut(index).get_memory_mut();
+            memory.write_byte(0xFF0F, 0x00);
+            memory.write_byte(0xFF01, sb);
+            memory.write_byte(0xFF02, sc);
+        }
+        // the second console starts clocking before the first one has started listening
+        pair.run_until(8192);
+        assert_eq!(pair.get(0).get_memory().read_byte(0xFF01), 0x12);
+        assert_eq!(pair.get(1).get_memory().read_byte(0xFF01), 0xFF);
+
+        pair.get_mut(1).get_memory_mut().write_byte(0xFF01, 0x34);
+        pair.get_mut(1).get_memory_mut().write_byte(0xFF02, 0x81);
+        pair.run_until(16384);
+        let [first, second] = pair.into_inner();
+        assert_eq!(first.get_memory().read_byte(0xFF01), 0x34);
+        assert_eq!(second.get_memory().read_byte(0xFF01), 0x12);
+        for console in [first, second] {
+            assert_eq!(console.get_memory().read_byte(0xFF0F) & INTERRUPT_SERIAL, INTERRUPT_SERIAL);
+            assert_eq!(console.get_memory().read_byte(0xFF02) & 0x80, 0);
+        }
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || LinkCable::connect_tcp(address).unwrap());
+        let server = LinkCable::accept_tcp(&listener).unwrap();
+        assert_eq!(exchange(server, client.join().unwrap()), (0x34, 0x12));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        let path = std::env::temp_dir().join(format!("crabboy-link-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || LinkCable::connect_unix(path).unwrap())
+        };
+        let server = LinkCable::accept_unix(&listener).unwrap();
+        // the listening side can just as well be the one that connected
+        assert_eq!(exchange(client.join().unwrap(), server), (0x34, 0x12));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_both_clocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            run_side(LinkCable::connect_tcp(address).unwrap(), 0x56, 0x81, 2048)
+        });
+        // two consoles driving the clock hear nothing from each other
+        let server = run_side(LinkCable::accept_tcp(&listener).unwrap(), 0x12, 0x81, 2048);
+        assert_eq!((server, client.join().unwrap()), (0xFF, 0xFF));
+    }
+}
@@ -1,14 +1,200 @@
 use crabboy::dmgcpu::DMGCPU;
-use std::time::Duration;
-use std::thread;
-
-const CPU_SPEED: u32 = 4_190_000;   // cpu clock speed in Hz
+use crabboy::headless::HeadlessRunner;
+use crabboy::link::LinkCable;
+use crabboy::model::Model;
+use crabboy::movie::{Movie, MovieError, MoviePlayer};
+use crabboy::printer::{PrintFormat, Printer};
+use crabboy::ppu::Renderer;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::rc::Rc;
 
 fn main() {
+    let mut model: Option<Model> = None;
+    let mut renderer = Renderer::Scanline;
+    let mut color_correction = false;
+    let mut headless = false;
+    let mut cycle_limit: Option<u64> = None;
+    let mut wav: Option<String> = None;
+    let mut wav_stems = false;
+    let mut link: Option<(bool, String)> = None;   // (listen, address)
+    let mut printer: Option<String> = None;
+    let mut print_format = PrintFormat::Png;
+    let mut movie: Option<String> = None;
+    let mut rom_path: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => {
+                let value = args.next().unwrap_or_default();
+                model = Some(value.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                }));
+            },
+            "--renderer" => {
+                let value = args.next().unwrap_or_default();
+                renderer = value.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                });
+            },
+            "--color-correction" => color_correction = true,
+            "--headless" => headless = true,
+            "--wav" => wav = Some(args.next().unwrap_or_default()),
+            "--wav-stems" => wav_stems = true,
+            "--link-listen" => link = Some((true, args.next().unwrap_or_default())),
+            "--link-connect" => link = Some((false, args.next().unwrap_or_default())),
+            "--movie" => movie = Some(args.next().unwrap_or_default()),
+            "--printer" => printer = Some(args.next().unwrap_or_default()),
+            "--print-format" => {
+                let value = args.next().unwrap_or_default();
+                print_format = value.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(2);
+                });
+            },
+            "--cycle-limit" => {
+                let value = args.next().unwrap_or_default();
+                cycle_limit = Some(value.parse().unwrap_or_else(|_| {
+                    eprintln!("invalid cycle limit: {}", value);
+                    process::exit(2);
+                }));
+            },
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    let Some(path) = rom_path else {
+        eprintln!("usage: crabboy [--model MODEL] [--renderer RENDERER] [--color-correction] \
+                   [--headless [--cycle-limit CYCLES] [--wav FILE [--wav-stems]]] [--link-listen|--link-connect ADDRESS] \
+                   [--printer DIRECTORY [--print-format png|pgm]] [--movie FILE] <rom>");
+        process::exit(2);
+    };
+    let rom = fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path, e);
+        process::exit(2);
+    });
+
+    if let Some(path) = movie {
+        process::exit(play_movie(&path, &rom));
+    }
+
+    let mut gbc = DMGCPU::with_model(model.unwrap_or_else(|| Model::detect(&rom)));
+    gbc.load_rom(&rom);
+    gbc.set_renderer(renderer);
+    gbc.set_color_correction(color_correction);
+
+    let mut printer_handle = None;
+    if let Some((listen, address)) = link {
+        let cable = open_link(listen, &address).unwrap_or_else(|e| {
+            eprintln!("link cable on {} failed: {}", address, e);
+            process::exit(2);
+        });
+        gbc.attach_serial(Box::new(cable));
+    } else if let Some(directory) = printer {
+        // kept to report pages that failed to save
+        let printer = Rc::new(RefCell::new(Printer::new(directory, print_format)));
+        gbc.attach_serial(Box::new(Rc::clone(&printer)));
+        printer_handle = Some(printer);
+    }
+
+    if headless {
+        // run a test ROM to completion and report its serial output
+        let mut runner = HeadlessRunner::new(gbc);
+        if let Some(cycles) = cycle_limit {
+            runner.set_cycle_limit(cycles);
+        }
+        if let Some(path) = &wav {
+            runner.record_audio(path, wav_stems).unwrap_or_else(|e| {
+                eprintln!("failed to create {}: {}", path, e);
+                process::exit(2);
+            });
+        }
+        let outcome = runner.run();
+        if let Err(e) = runner.finish_audio() {
+            eprintln!("failed to write audio: {}", e);
+        }
+        print!("{}", runner.get_output());
+        println!();
+        println!("{:?} after {} cpu cycles", outcome, runner.get_cpu().get_cycle_count());
+        if let Some(opcode) = runner.get_cpu().get_unimplemented_opcode() {
+            println!("stopped at opcode {:02X}, which isn't emulated yet", opcode);
+        }
+        report_printer(printer_handle.as_deref());
+        process::exit(outcome.exit_code());
+    }
 
-    let mut gbc = DMGCPU::new(CPU_SPEED);
     gbc.run();
+    report_printer(printer_handle.as_deref());
 
     println!("Total clock cycles: {}", gbc.get_cpu_clock().get_total_cycles());
     println!("Total cpu cycles: {}", gbc.get_cycle_count());
 }
+
+// pages the printer couldn't save get one more try before the emulator exits
+fn report_printer(printer: Option<&RefCell<Printer>>) {
+    let Some(printer) = printer else {
+        return;
+    };
+    let mut printer = printer.borrow_mut();
+    if let Some(e) = printer.take_last_error() {
+        eprintln!("printer: failed to save a page: {}", e);
+    }
+    if let Err(e) = printer.save_unsaved() {
+        eprintln!("printer: {} pages weren't saved: {}", printer.get_unsaved_pages(), e);
+    }
+}
+
+// play a movie back as fast as possible, checking that it stays in sync. the movie picks the
+// renderer and color correction, they're part of what gets hashed
+fn play_movie(path: &str, rom: &[u8]) -> i32 {
+    let data = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path, e);
+        process::exit(2);
+    });
+    // crabboy's own movies, or another emulator's converted to one
+    let movie = match Movie::parse(&data) {
+        Err(MovieError::NotAMovie) => Movie::import(&data, rom).map(|import| {
+            for feature in &import.unsupported {
+                eprintln!("warning: movie uses {}, playback may desync", feature);
+            }
+            import.movie
+        }),
+        result => result,
+    };
+    let movie = movie.unwrap_or_else(|e| {
+        eprintln!("failed to load {}: {}", path, e);
+        process::exit(2);
+    });
+    let mut gbc = movie.start(rom).unwrap_or_else(|e| {
+        eprintln!("failed to start {}: {}", path, e);
+        process::exit(2);
+    });
+
+    let hashes = movie.get_hashes().len();
+    match MoviePlayer::new(movie).play(&mut gbc) {
+        Ok(frames) => {
+            println!("played {} frames, {} state hashes matched", frames, hashes);
+            0
+        },
+        Err(desync) => {
+            println!("{}", desync);
+            1
+        },
+    }
+}
+
+// host:port for TCP, unix:path for a Unix domain socket
+fn open_link(listen: bool, address: &str) -> io::Result<LinkCable> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        return if listen { LinkCable::listen_unix(path) } else { LinkCable::connect_unix(path) };
+    }
+
+    if listen { LinkCable::listen_tcp(address) } else { LinkCable::connect_tcp(address) }
+}
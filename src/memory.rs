@@ -1,30 +1,702 @@
+use crate::apu::{self, Apu};
+use crate::cartridge::CartridgeHeader;
+use crate::joypad::Joypad;
+use crate::model::Model;
+use crate::ppu::{Mode, Ppu};
+use crate::serial::Serial;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::timer::Timer;
+use self::dma::{OamDma, VramDma};
+
+mod dma;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const ROM_END: usize = 0x8000;  // no MBC yet, so only the first two banks are mapped
+
+pub const INTERRUPT_VBLANK: u8 = 0x01;
+pub const INTERRUPT_STAT: u8 = 0x02;
+pub const INTERRUPT_TIMER: u8 = 0x04;
+pub const INTERRUPT_SERIAL: u8 = 0x08;
+pub const INTERRUPT_JOYPAD: u8 = 0x10;
+
+const IF_ADDRESS: usize = 0xFF0F;
+const IE_ADDRESS: usize = 0xFFFF;
+
+const CARTRIDGE_HEADER: std::ops::Range<usize> = 0x0134..0x0150;   // title through checksums
+
+// the sections save_state writes, a state missing any of them was cut short
+pub(crate) const STATE_SECTIONS: [&[u8; 4]; 7] = [b"MEM ", b"DMA ", b"PPU ", b"APU ", b"TIMR", b"JOYP", b"SERL"];
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+const KEY1_PREPARE: u8 = 0x01;
+
 pub struct Memory {
-    memory: [u8; 0xFFFF]
+    memory: [u8; 0x10000],
+    model: Model,
+    cgb_mode: bool,
+    ppu: Ppu,
+    apu: Apu,
+    timer: Timer,
+    joypad: Joypad,
+    serial: Serial,
+    wram: Vec<u8>,
+    wram_bank: u8,      // SVBK, bank mapped at 0xD000
+    double_speed: bool,
+    key1: u8,           // speed switch armed by writing bit 0, performed by STOP
+    rp: u8,             // infrared port
+    undocumented: [u8; 4],  // 0xFF72-0xFF75
+    oam_dma: OamDma,
+    vram_dma: VramDma,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Memory {
+        Memory::with_model(Model::Dmg)
+    }
+
+    pub fn with_model(model: Model) -> Memory {
         Memory {
-            memory: [0; 0xFFFF]
+            memory: [0; 0x10000],
+            model,
+            cgb_mode: false,
+            ppu: Ppu::new(model),
+            apu: Apu::new(model),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            wram: vec![0; WRAM_BANK_SIZE * model.wram_banks()],
+            wram_bank: 1,
+            double_speed: false,
+            key1: 0,
+            rp: 0,
+            undocumented: [0; 4],
+            oam_dma: OamDma::new(),
+            vram_dma: VramDma::new(),
+        }
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    // true when CGB hardware runs a cartridge that enables CGB features,
+    // false on monochrome hardware and in DMG compatibility mode
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn get_buttons(&self) -> u8 {
+        self.joypad.get_buttons()
+    }
+
+    // held buttons as a mask of Button bits
+    pub fn set_buttons(&mut self, pressed: u8) {
+        let interrupts = self.joypad.set_buttons(pressed);
+        self.request_interrupt(interrupts);
+    }
+
+    pub fn get_serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn get_apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn get_apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn get_ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn get_ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    // map a ROM image into 0x0000-0x7FFF and select CGB or compatibility mode from its header
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        let len = rom.len().min(ROM_END);
+        self.memory[..len].copy_from_slice(&rom[..len]);
+
+        self.cgb_mode = self.model.is_cgb()
+            && CartridgeHeader::parse(rom).is_some_and(|header| header.supports_cgb());
+        self.ppu.set_cgb_mode(self.cgb_mode);
+        self.serial.set_cgb_mode(self.cgb_mode);
+    }
+
+    // I/O state the boot ROM leaves behind
+    pub fn boot(&mut self) {
+        self.ppu.boot();
+        self.apu.boot();
+        self.timer.boot(self.model.boot_div());
+        self.joypad.boot();
+        self.memory[IF_ADDRESS] = INTERRUPT_VBLANK;
+    }
+
+    // advance the hardware by the given number of clock cycles
+    pub fn tick(&mut self, cycles: u8) {
+        let counter = self.timer.get_counter();
+        let interrupts = self.timer.tick(cycles) | self.serial.tick(cycles);
+        self.request_interrupt(interrupts);
+        if counter & self.frame_sequencer_bit() != 0
+            && self.timer.get_counter() & self.frame_sequencer_bit() == 0 {
+            self.apu.clock_frame_sequencer();
+        }
+
+        // sound runs at the same rate in both speeds
+        self.apu.tick(if self.double_speed { cycles / 2 } else { cycles });
+
+        self.oam_dma.tick(cycles);
+        while let Some((source, index)) = self.oam_dma.next_copy() {
+            let value = self.read_dma_source(source);
+            self.oam_dma.set_value(value);
+            self.ppu.dma_write_oam(index, value);
+        }
+
+        // the PPU keeps its pace when the CPU runs at double speed
+        let dots = if self.double_speed { cycles / 2 } else { cycles };
+        let was_hblank = self.in_hblank();
+        let interrupts = self.ppu.tick(dots);
+        self.request_interrupt(interrupts);
+        if self.in_hblank() != was_hblank {
+            self.vram_dma.set_hblank(!was_hblank);
+        }
+    }
+
+    // performs any CGB VRAM DMA that is due and returns the clock cycles the CPU is stalled for,
+    // HDMA waits while the CPU is halted
+    pub fn run_vram_dma(&mut self, halted: bool) -> u16 {
+        let blocks = self.vram_dma.due_blocks(halted);
+        for _ in 0..blocks {
+            let (source, destination) = self.vram_dma.next_block();
+            for offset in 0..dma::VRAM_DMA_BLOCK {
+                let value = self.read_bus(source.wrapping_add(offset));
+                self.ppu.write_vram(0x8000 | (destination + offset), value);
+            }
         }
+        dma::vram_dma_stall(blocks, self.double_speed)
     }
 
+    // executed by STOP: toggles CGB double speed if KEY1 armed it, returns true if it did
+    pub fn speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || self.key1 & KEY1_PREPARE == 0 {
+            return false;
+        }
+        self.reset_div();
+        self.double_speed = !self.double_speed;
+        self.key1 = 0;
+        true
+    }
+
+    pub fn request_interrupt(&mut self, interrupts: u8) {
+        self.memory[IF_ADDRESS] |= interrupts;
+    }
+
+    pub fn clear_interrupt(&mut self, interrupts: u8) {
+        self.memory[IF_ADDRESS] &= !interrupts;
+    }
+
+    // interrupts that are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.memory[IF_ADDRESS] & self.memory[IE_ADDRESS] & 0x1F
+    }
+
+    // a CPU read, which sees the DMA's byte instead when it shares the bus with a transfer
     pub fn read_byte(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+        if self.dma_conflict(address) {
+            return if address >= 0xFE00 { 0xFF } else { self.oam_dma.get_value() };
+        }
+        self.read_bus(address)
     }
 
     pub fn read_word(&self, address: u16) -> u16 {
         u16::from_le_bytes([
-            self.memory[address as usize],
-            self.memory[(address + 1) as usize]
+            self.read_byte(address),
+            self.read_byte(address.wrapping_add(1))
         ])
     }
 
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        if self.dma_conflict(address) {
+            return;
+        }
+
+        match address {
+            0x8000..=0x9FFF => self.ppu.write_vram(address, value),
+            0xC000..=0xFDFF => {
+                let index = self.wram_index(address);
+                self.wram[index] = value;
+            },
+            0xFE00..=0xFE9F => self.ppu.write_oam(address, value),
+            0xFF00 => {
+                let interrupts = self.joypad.write_register(value);
+                self.request_interrupt(interrupts);
+            },
+            0xFF01..=0xFF02 => self.serial.write_register(address, value),
+            0xFF04 => self.reset_div(),
+            0xFF05..=0xFF07 => self.timer.write_register(address, value),
+            0xFF0F => self.memory[IF_ADDRESS] = value & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write_register(address, value),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.ppu.write_register(address, value)
+            },
+            0xFF46 => self.oam_dma.write_register(value),
+            0xFF51..=0xFF55 if self.cgb_mode => self.vram_dma.write_register(address, value),
+            0xFF4C..=0xFF4D | 0xFF56 | 0xFF70 | 0xFF72..=0xFF77 => {
+                self.write_cgb_register(address, value)
+            },
+            _ => self.memory[address as usize] = value,
+        }
+    }
+
     pub fn write(&mut self, address: usize, data: &[u8]) {
         // Ensure the address is within bounds
         assert!(address + data.len() <= self.memory.len(), "Address out of bounds");
 
         // Write data starting at the specified address
-        self.memory[address..(address + data.len())].copy_from_slice(data);
+        for (offset, &value) in data.iter().enumerate() {
+            self.write_byte((address + offset) as u16, value);
+        }
+    }
+
+    // the loaded cartridge's header, which identifies the ROM a save state belongs to
+    pub(crate) fn get_cartridge_header(&self) -> &[u8] {
+        &self.memory[CARTRIDGE_HEADER]
+    }
+
+    // one section for the bus and one for every component on it, ROM isn't included
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"MEM ", |state| {
+            state.write_bool(self.cgb_mode);
+            state.write_bytes(&self.memory[ROM_END..]);
+            state.write_vec(&self.wram);
+            state.write_u8(self.wram_bank);
+            state.write_bool(self.double_speed);
+            state.write_u8(self.key1);
+            state.write_u8(self.rp);
+            state.write_bytes(&self.undocumented);
+        });
+        state.section(b"DMA ", |state| {
+            self.oam_dma.save_state(state);
+            self.vram_dma.save_state(state);
+        });
+        state.section(b"PPU ", |state| self.ppu.save_state(state));
+        state.section(b"APU ", |state| self.apu.save_state(state));
+        state.section(b"TIMR", |state| self.timer.save_state(state));
+        state.section(b"JOYP", |state| self.joypad.save_state(state));
+        state.section(b"SERL", |state| self.serial.save_state(state));
+    }
+
+    // restores a section written by save_state, ignoring the ones it doesn't know
+    pub(crate) fn load_section(&mut self, tag: &[u8; 4], state: &mut StateReader) -> Result<(), StateError> {
+        match tag {
+            b"MEM " => {
+                self.cgb_mode = state.read_bool()?;
+                self.serial.set_cgb_mode(self.cgb_mode);
+                state.read_bytes(&mut self.memory[ROM_END..])?;
+                let wram = state.read_vec()?;
+                if wram.len() != self.wram.len() {
+                    return Err(StateError::Invalid("WRAM size"));
+                }
+                self.wram = wram;
+                self.wram_bank = state.read_u8()?;
+                if self.wram_bank == 0 || self.wram_bank as usize >= self.model.wram_banks() {
+                    return Err(StateError::Invalid("WRAM bank"));
+                }
+                self.double_speed = state.read_bool()?;
+                self.key1 = state.read_u8()?;
+                self.rp = state.read_u8()?;
+                state.read_bytes(&mut self.undocumented)
+            },
+            b"DMA " => {
+                self.oam_dma.load_state(state)?;
+                self.vram_dma.load_state(state)
+            },
+            b"PPU " => self.ppu.load_state(state),
+            b"APU " => self.apu.load_state(state),
+            b"TIMR" => self.timer.load_state(state),
+            b"JOYP" => self.joypad.load_state(state),
+            b"SERL" => self.serial.load_state(state),
+            _ => Ok(()),
+        }
+    }
+
+    /* ----- PRIVATE ----- */
+    // the memory map as seen without any DMA in the way
+    fn read_bus(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xC000..=0xFDFF => self.wram[self.wram_index(address)],
+            0xFE00..=0xFE9F => self.ppu.read_oam(address),
+            0xFF00 => self.joypad.read_register(),
+            0xFF01..=0xFF02 => self.serial.read_register(address),
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            0xFF0F => self.memory[IF_ADDRESS] | 0xE0,
+            0xFF10..=0xFF3F => self.apu.read_register(address),
+            0xFF46 => self.oam_dma.read_register(),
+            0xFF55 if self.cgb_mode => self.vram_dma.read_length(),
+            0xFF51..=0xFF55 => 0xFF,
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B | 0xFF4F | 0xFF68..=0xFF6C => {
+                self.ppu.read_register(address)
+            },
+            0xFF4C..=0xFF4D | 0xFF56 | 0xFF70 | 0xFF72..=0xFF77 => self.read_cgb_register(address),
+            _ => self.memory[address as usize],
+        }
+    }
+
+    // 0xC000-0xCFFF is always bank 0, 0xD000-0xDFFF the SVBK bank, 0xE000-0xFDFF echoes both
+    fn wram_index(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) & 0x1FFF;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank as usize * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
     }
-}
\ No newline at end of file
+
+    // the frame sequencer watches DIV bit 4, bit 5 in double speed
+    fn frame_sequencer_bit(&self) -> u16 {
+        if self.double_speed { apu::FRAME_SEQUENCER_BIT_DOUBLE_SPEED } else { apu::FRAME_SEQUENCER_BIT }
+    }
+
+    // clearing the system counter is a falling edge for the frame sequencer too
+    fn reset_div(&mut self) {
+        if self.timer.get_counter() & self.frame_sequencer_bit() != 0 {
+            self.apu.clock_frame_sequencer();
+        }
+        self.timer.write_register(0xFF04, 0);
+    }
+
+    fn in_hblank(&self) -> bool {
+        self.ppu.is_lcd_enabled() && self.ppu.get_mode() == Mode::HBlank
+    }
+
+    // OAM is unreachable during a transfer, as is whatever shares the bus it reads from
+    fn dma_conflict(&self, address: u16) -> bool {
+        if !self.oam_dma.is_active() {
+            return false;
+        }
+        match address {
+            0xFE00..=0xFEFF => true,
+            _ => dma::bus(address).is_some() && dma::bus(address) == self.oam_dma.conflict_bus(),
+        }
+    }
+
+    // sources from 0xE000 up read echo RAM on DMG and cartridge RAM on CGB
+    fn read_dma_source(&self, source: u16) -> u8 {
+        match source {
+            0xE000..=0xFFFF if self.model.dma_mirrors_echo_ram() => self.read_bus(source - 0x2000),
+            0xE000..=0xFFFF => self.read_bus(source - 0x4000),
+            _ => self.read_bus(source),
+        }
+    }
+
+    // CGB system registers; on DMG hardware they are all open bus
+    fn read_cgb_register(&self, address: u16) -> u8 {
+        if !self.model.is_cgb() {
+            return 0xFF;
+        }
+
+        match address {
+            0xFF4D if self.cgb_mode => ((self.double_speed as u8) << 7) | 0x7E | self.key1,
+            0xFF56 if self.cgb_mode => {
+                // bit 1 reads 1 as long as no infrared light is received
+                0x3C | (self.rp & 0xC1) | 0x02
+            },
+            0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank,
+            0xFF72 | 0xFF73 => self.undocumented[(address - 0xFF72) as usize],
+            0xFF74 if self.cgb_mode => self.undocumented[2],
+            0xFF75 => 0x8F | (self.undocumented[3] & 0x70),
+            // digital outputs of the sound channels
+            0xFF76 => self.apu.read_pcm12(),
+            0xFF77 => self.apu.read_pcm34(),
+            // KEY0 is locked once the boot ROM has picked CGB or compatibility mode
+            _ => 0xFF,
+        }
+    }
+
+    fn write_cgb_register(&mut self, address: u16, value: u8) {
+        if !self.model.is_cgb() {
+            return;
+        }
+
+        match address {
+            0xFF4D if self.cgb_mode => self.key1 = value & KEY1_PREPARE,
+            0xFF56 if self.cgb_mode => self.rp = value,
+            0xFF70 if self.cgb_mode => {
+                // bank 0 can't be mapped at 0xD000, selecting it gives bank 1
+                self.wram_bank = (value & 0x07).max(1);
+            },
+            0xFF72 | 0xFF73 => self.undocumented[(address - 0xFF72) as usize] = value,
+            0xFF74 if self.cgb_mode => self.undocumented[2] = value,
+            0xFF75 => self.undocumented[3] = value,
+            _ => {},
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgb_memory(cgb_flag: u8) -> Memory {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = cgb_flag;
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.load_rom(&rom);
+        memory.boot();
+        memory
+    }
+
+    #[test]
+    fn test_wram_banks() {
+        let mut memory = cgb_memory(0x80);
+        assert_eq!(memory.read_byte(0xFF70), 0xF9);
+        memory.write_byte(0xC000, 0x11);
+        memory.write_byte(0xD000, 0x22);
+
+        memory.write_byte(0xFF70, 0x07);
+        assert_eq!(memory.read_byte(0xFF70), 0xFF);
+        assert_eq!(memory.read_byte(0xD000), 0x00);
+        memory.write_byte(0xD000, 0x77);
+        // bank 0 stays fixed and echo RAM follows the selected bank
+        assert_eq!(memory.read_byte(0xC000), 0x11);
+        assert_eq!(memory.read_byte(0xF000), 0x77);
+
+        // selecting bank 0 maps bank 1
+        memory.write_byte(0xFF70, 0x00);
+        assert_eq!(memory.read_byte(0xFF70), 0xF9);
+        assert_eq!(memory.read_byte(0xD000), 0x22);
+        assert_eq!(memory.read_byte(0xE000), 0x11);
+    }
+
+    #[test]
+    fn test_wram_compat() {
+        let mut memory = cgb_memory(0x00);
+        memory.write_byte(0xD000, 0x22);
+        memory.write_byte(0xFF70, 0x03);
+        assert_eq!(memory.read_byte(0xFF70), 0xFF);
+        assert_eq!(memory.read_byte(0xD000), 0x22);
+    }
+
+    #[test]
+    fn test_cgb_registers() {
+        let mut memory = cgb_memory(0x80);
+        for address in [0xFF4D, 0xFF56, 0xFF72, 0xFF73, 0xFF74, 0xFF75] {
+            memory.write_byte(address, 0xFF);
+        }
+        assert_eq!(memory.read_byte(0xFF4C), 0xFF);
+        assert_eq!(memory.read_byte(0xFF4D), 0x7F);
+        assert_eq!(memory.read_byte(0xFF56), 0xFF);
+        assert_eq!(memory.read_byte(0xFF72), 0xFF);
+        assert_eq!(memory.read_byte(0xFF74), 0xFF);
+        assert_eq!(memory.read_byte(0xFF75), 0xFF);
+        memory.write_byte(0xFF56, 0x00);
+        memory.write_byte(0xFF72, 0x00);
+        memory.write_byte(0xFF74, 0x00);
+        memory.write_byte(0xFF75, 0x00);
+        assert_eq!(memory.read_byte(0xFF56), 0x3E);
+        assert_eq!(memory.read_byte(0xFF72), 0x00);
+        assert_eq!(memory.read_byte(0xFF74), 0x00);
+        assert_eq!(memory.read_byte(0xFF75), 0x8F);
+        assert_eq!(memory.read_byte(0xFF6C), 0xFE);
+
+        // compatibility mode hides the CGB-only registers but keeps the undocumented ones
+        let mut memory = cgb_memory(0x00);
+        for address in [0xFF4D, 0xFF56, 0xFF72, 0xFF74, 0xFF75] {
+            memory.write_byte(address, 0x00);
+        }
+        assert_eq!(memory.read_byte(0xFF4D), 0xFF);
+        assert_eq!(memory.read_byte(0xFF56), 0xFF);
+        assert_eq!(memory.read_byte(0xFF72), 0x00);
+        assert_eq!(memory.read_byte(0xFF74), 0xFF);
+        assert_eq!(memory.read_byte(0xFF75), 0x8F);
+        assert_eq!(memory.read_byte(0xFF6C), 0xFF);
+        assert!(!memory.speed_switch());
+
+        // none of them exist on DMG hardware
+        let mut memory = Memory::new();
+        for address in [0xFF4C, 0xFF4D, 0xFF56, 0xFF6C, 0xFF70, 0xFF72, 0xFF75] {
+            memory.write_byte(address, 0x00);
+            assert_eq!(memory.read_byte(address), 0xFF, "{:04X}", address);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut memory = Memory::new();
+        for i in 0..0xA0 {
+            memory.write_byte(0xC100 + i, i as u8);
+        }
+        memory.write_byte(0xFF80, 0x42);
+        memory.write_byte(0xFF46, 0xC1);
+        assert_eq!(memory.read_byte(0xFF46), 0xC1);
+        memory.tick(8);
+
+        // while copying, the external bus and OAM are off limits but HRAM and VRAM are not
+        assert_eq!(memory.read_byte(0x0000), 0x00);
+        assert_eq!(memory.read_byte(0xD000), 0x00);
+        assert_eq!(memory.read_byte(0xFE00), 0xFF);
+        assert_eq!(memory.read_byte(0xFF80), 0x42);
+        memory.write_byte(0xC100, 0xAA);
+        memory.write_byte(0x8000, 0x55);
+        assert_eq!(memory.read_byte(0x8000), 0x55);
+
+        for _ in 0..159 {
+            memory.tick(4);
+        }
+        assert_eq!(memory.read_byte(0xC100), 0x00);
+        assert_eq!(memory.read_byte(0xFE00), 0x00);
+        assert_eq!(memory.read_byte(0xFE9F), 0x9F);
+    }
+
+    #[test]
+    fn test_oam_dma_high_source() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xDE00, 0x12);
+        memory.write_byte(0xFF46, 0xFE);
+        for _ in 0..161 {
+            memory.tick(4);
+        }
+        assert_eq!(memory.read_byte(0xFE00), 0x12);
+
+        // CGB reads cartridge RAM instead of echo RAM
+        let mut memory = Memory::with_model(Model::Cgb);
+        memory.write_byte(0xDE00, 0x12);
+        memory.write_byte(0xBE00, 0x34);
+        memory.write_byte(0xFF46, 0xFE);
+        for _ in 0..161 {
+            memory.tick(4);
+        }
+        assert_eq!(memory.read_byte(0xFE00), 0x34);
+    }
+
+    fn write_hdma(memory: &mut Memory, source: u16, destination: u16) {
+        memory.write_byte(0xFF51, (source >> 8) as u8);
+        memory.write_byte(0xFF52, source as u8);
+        memory.write_byte(0xFF53, (destination >> 8) as u8);
+        memory.write_byte(0xFF54, destination as u8);
+    }
+
+    #[test]
+    fn test_gdma() {
+        let mut memory = cgb_memory(0x80);
+        memory.write_byte(0xFF40, 0x00);
+        for i in 0..0x20 {
+            memory.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        write_hdma(&mut memory, 0xC000, 0x8100);
+        memory.write_byte(0xFF55, 0x01);
+        assert_eq!(memory.run_vram_dma(false), 64);
+        assert_eq!(memory.read_byte(0xFF55), 0xFF);
+        assert_eq!(memory.read_byte(0x8100), 0x01);
+        assert_eq!(memory.read_byte(0x811F), 0x20);
+        assert_eq!(memory.run_vram_dma(false), 0);
+
+        // the registers carry on from where the last transfer ended
+        memory.write_byte(0xFF4D, 0x01);
+        assert!(memory.speed_switch());
+        memory.write_byte(0xFF55, 0x00);
+        assert_eq!(memory.run_vram_dma(false), 64);
+        assert_eq!(memory.read_byte(0x8120), 0x00);
+
+        // unavailable in compatibility mode
+        let mut memory = cgb_memory(0x00);
+        write_hdma(&mut memory, 0xC000, 0x8000);
+        memory.write_byte(0xFF55, 0x00);
+        assert_eq!(memory.read_byte(0xFF55), 0xFF);
+        assert_eq!(memory.run_vram_dma(false), 0);
+    }
+
+    #[test]
+    fn test_hdma() {
+        let mut memory = cgb_memory(0x80);
+        for i in 0..0x30 {
+            memory.write_byte(0xC000 + i, 0xAA);
+        }
+        // start on a line's mode 2, the first HBlank is 80 + 172 dots away
+        while memory.get_ppu().get_mode() != Mode::OamScan {
+            memory.tick(4);
+        }
+        write_hdma(&mut memory, 0xC000, 0x8000);
+        memory.write_byte(0xFF55, 0x82);
+        assert_eq!(memory.read_byte(0xFF55), 0x02);
+
+        for _ in 0..252 / 4 - 1 {
+            memory.tick(4);
+            assert_eq!(memory.run_vram_dma(false), 0);
+        }
+        memory.tick(4);
+        // the CPU is halted, so this HBlank passes without a transfer
+        assert_eq!(memory.run_vram_dma(true), 0);
+        while memory.get_ppu().get_mode() == Mode::HBlank {
+            memory.tick(4);
+        }
+        while memory.get_ppu().get_mode() != Mode::HBlank {
+            memory.tick(4);
+        }
+        assert_eq!(memory.run_vram_dma(false), 32);
+        assert_eq!(memory.run_vram_dma(false), 0);
+        assert_eq!(memory.read_byte(0xFF55), 0x01);
+        assert_eq!(memory.read_byte(0x800F), 0xAA);
+        assert_eq!(memory.read_byte(0x8010), 0x00);
+
+        // cancelling keeps the remaining length with bit 7 set
+        memory.write_byte(0xFF55, 0x00);
+        assert_eq!(memory.read_byte(0xFF55), 0x81);
+    }
+
+    #[test]
+    fn test_frame_sequencer() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xFF26, 0x80);
+        memory.write_byte(0xFF17, 0xF0);
+        memory.write_byte(0xFF16, 0x3F);
+        memory.write_byte(0xFF19, 0xC0);
+        assert_eq!(memory.read_byte(0xFF26), 0xF2);
+
+        // DIV bit 4 falls every 8192 cycles, the first sequencer step clocks length
+        for _ in 0..8192 / 4 - 1 {
+            memory.tick(4);
+        }
+        assert_eq!(memory.read_byte(0xFF26), 0xF2);
+        memory.tick(4);
+        assert_eq!(memory.read_byte(0xFF26), 0xF0);
+
+        // resetting DIV with the bit set clocks it as well
+        for _ in 0..(8192 + 4096) / 4 {
+            memory.tick(4);
+        }
+        memory.write_byte(0xFF16, 0x3F);
+        memory.write_byte(0xFF19, 0xC0);
+        assert_eq!(memory.read_byte(0xFF26), 0xF2);
+        memory.write_byte(0xFF04, 0x00);
+        assert_eq!(memory.read_byte(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut memory = Memory::new();
+        memory.boot();
+        assert_eq!(memory.read_byte(0xFF04), 0xAB);
+
+        memory.write_byte(0xFF0F, 0x00);
+        memory.write_byte(0xFF05, 0xFF);
+        memory.write_byte(0xFF07, 0x05);
+        memory.tick(20);
+        assert_eq!(memory.read_byte(0xFF0F) & INTERRUPT_TIMER, INTERRUPT_TIMER);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+pub(super) const OAM_DMA_LENGTH: u8 = 0xA0;    // bytes copied by one transfer, one per M-cycle
+const OAM_DMA_SETUP: u8 = 1;                    // M-cycles between the write and the first byte
+pub(super) const VRAM_DMA_BLOCK: u16 = 0x10;   // bytes copied per HBlank
+const VRAM_DMA_BLOCK_CYCLES: u16 = 32;          // single speed CPU cycles the CPU is stalled per block
+const HDMA_MODE: u8 = 0x80;
+
+/* ----- TYPE DECLARATIONS ----- */
+// the two address buses a DMA can occupy, HRAM and I/O sit on neither
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Bus {
+    External,   // cartridge and WRAM
+    Video,
+}
+
+// copies 0xXX00-0xXX9F into OAM after a write of XX to 0xFF46
+pub(super) struct OamDma {
+    register: u8,           // last value written to 0xFF46
+    source: u16,
+    index: u8,              // next byte to copy, OAM_DMA_LENGTH while idle
+    pending: Option<(u16, u8)>, // requested transfer and its remaining setup M-cycles
+    value: u8,              // last byte moved, what conflicting reads see
+    cycles: u16,            // T-cycles not yet making up an M-cycle
+}
+
+// CGB VRAM DMA behind HDMA1-HDMA5, either all at once (GDMA) or a block per HBlank (HDMA)
+pub(super) struct VramDma {
+    source: u16,
+    destination: u16,       // offset into VRAM
+    remaining: u8,          // blocks left to copy
+    general: bool,          // a GDMA was requested and has yet to run
+    hblank: bool,           // an HDMA is in progress
+    hblank_ready: bool,     // the current HBlank still owes its block
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl OamDma {
+    pub(super) fn new() -> OamDma {
+        OamDma {
+            register: 0xFF,
+            source: 0,
+            index: OAM_DMA_LENGTH,
+            pending: None,
+            value: 0xFF,
+            cycles: 0,
+        }
+    }
+
+    pub(super) fn read_register(&self) -> u8 {
+        self.register
+    }
+
+    // a write during a transfer restarts it, the old one keeps running until the new one is set up
+    pub(super) fn write_register(&mut self, value: u8) {
+        self.register = value;
+        self.pending = Some(((value as u16) << 8, OAM_DMA_SETUP));
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.index < OAM_DMA_LENGTH
+    }
+
+    // the bus the transfer is reading from, if it is running
+    pub(super) fn conflict_bus(&self) -> Option<Bus> {
+        if self.is_active() { bus(self.source) } else { None }
+    }
+
+    pub(super) fn get_value(&self) -> u8 {
+        self.value
+    }
+
+    pub(super) fn set_value(&mut self, value: u8) {
+        self.value = value;
+    }
+
+    // advance by T-cycles, the copies are then collected with next_copy
+    pub(super) fn tick(&mut self, cycles: u8) {
+        if self.is_active() || self.pending.is_some() {
+            self.cycles += cycles as u16;
+        }
+    }
+
+    // the next (source address, OAM index) pair due, consuming the M-cycles up to it
+    pub(super) fn next_copy(&mut self) -> Option<(u16, u8)> {
+        while self.cycles >= 4 {
+            self.cycles -= 4;
+            if let Some(copy) = self.step() {
+                return Some(copy);
+            }
+        }
+        None
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_u16(self.source);
+        state.write_u8(self.index);
+        state.write_bool(self.pending.is_some());
+        let (source, setup) = self.pending.unwrap_or_default();
+        state.write_u16(source);
+        state.write_u8(setup);
+        state.write_u8(self.value);
+        state.write_u16(self.cycles);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.source = state.read_u16()?;
+        self.index = state.read_u8()?.min(OAM_DMA_LENGTH);
+        let pending = state.read_bool()?;
+        let source = state.read_u16()?;
+        let setup = state.read_u8()?;
+        self.pending = if pending { Some((source, setup)) } else { None };
+        self.value = state.read_u8()?;
+        self.cycles = state.read_u16()?;
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    fn step(&mut self) -> Option<(u16, u8)> {
+        let copy = if self.is_active() {
+            let index = self.index;
+            self.index += 1;
+            Some((self.source + index as u16, index))
+        } else {
+            None
+        };
+
+        if let Some((source, delay)) = self.pending {
+            if delay <= 1 {
+                self.source = source;
+                self.index = 0;
+                self.pending = None;
+            } else {
+                self.pending = Some((source, delay - 1));
+            }
+        }
+
+        copy
+    }
+}
+
+impl VramDma {
+    pub(super) fn new() -> VramDma {
+        VramDma {
+            source: 0,
+            destination: 0,
+            remaining: 0,
+            general: false,
+            hblank: false,
+            hblank_ready: false,
+        }
+    }
+
+    pub(super) fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.destination = (self.destination & 0x00FF) | (((value & 0x1F) as u16) << 8),
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                if self.hblank && value & HDMA_MODE == 0 {
+                    // cancelled, the remaining length stays readable
+                    self.hblank = false;
+                } else {
+                    self.remaining = (value & 0x7F) + 1;
+                    self.general = value & HDMA_MODE == 0;
+                    self.hblank = !self.general;
+                    self.hblank_ready = false;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // HDMA5: bit 7 clear while an HDMA runs, then the blocks left minus one; 0xFF once done
+    pub(super) fn read_length(&self) -> u8 {
+        let length = self.remaining.wrapping_sub(1) & 0x7F;
+        if self.hblank { length } else { HDMA_MODE | length }
+    }
+
+    // called when the PPU enters or leaves HBlank on a visible line
+    pub(super) fn set_hblank(&mut self, hblank: bool) {
+        self.hblank_ready = hblank && self.hblank;
+    }
+
+    // blocks to copy right now: the whole GDMA, or one HDMA block if the CPU is running
+    pub(super) fn due_blocks(&mut self, halted: bool) -> u8 {
+        if self.hblank {
+            if !self.hblank_ready || halted {
+                return 0;
+            }
+            self.hblank_ready = false;
+            1
+        } else if self.general {
+            self.general = false;
+            self.remaining
+        } else {
+            0
+        }
+    }
+
+    // the (source, VRAM offset) of the next block, advancing the registers past it
+    pub(super) fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(VRAM_DMA_BLOCK);
+        self.destination = (self.destination + VRAM_DMA_BLOCK) & 0x1FF0;
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.hblank = false;
+        }
+        block
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.destination);
+        state.write_u8(self.remaining);
+        state.write_bool(self.general);
+        state.write_bool(self.hblank);
+        state.write_bool(self.hblank_ready);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()?;
+        self.destination = state.read_u16()?;
+        self.remaining = state.read_u8()?;
+        self.general = state.read_bool()?;
+        self.hblank = state.read_bool()?;
+        self.hblank_ready = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// CPU cycles lost to copying blocks, the DMA keeps its pace so double speed loses twice as many
+pub(super) fn vram_dma_stall(blocks: u8, double_speed: bool) -> u16 {
+    (blocks as u16 * VRAM_DMA_BLOCK_CYCLES) << double_speed as u16
+}
+
+pub(super) fn bus(address: u16) -> Option<Bus> {
+    match address {
+        0x8000..=0x9FFF => Some(Bus::Video),
+        0x0000..=0xFDFF => Some(Bus::External),
+        _ => None,
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(dma: &mut OamDma, cycles: u16) -> Vec<(u16, u8)> {
+        let mut copies = Vec::new();
+        for _ in 0..cycles / 4 {
+            dma.tick(4);
+            copies.extend(std::iter::from_fn(|| dma.next_copy()));
+        }
+        copies
+    }
+
+    #[test]
+    fn test_timing() {
+        let mut dma = OamDma::new();
+        dma.write_register(0xC1);
+        assert_eq!(dma.read_register(), 0xC1);
+
+        // one M-cycle of setup before the first byte
+        assert!(run(&mut dma, 4).is_empty());
+        assert!(dma.is_active());
+        let copies = run(&mut dma, 160 * 4);
+        assert_eq!(copies.len(), 160);
+        assert_eq!(copies[0], (0xC100, 0));
+        assert_eq!(copies[159], (0xC19F, 159));
+        assert!(!dma.is_active());
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut dma = OamDma::new();
+        dma.write_register(0xC0);
+        assert_eq!(run(&mut dma, 4 * 11).len(), 10);
+
+        // the old transfer copies one more byte while the new one is set up
+        dma.write_register(0xD0);
+        assert_eq!(run(&mut dma, 4), vec![(0xC00A, 10)]);
+        assert_eq!(run(&mut dma, 4), vec![(0xD000, 0)]);
+        assert_eq!(dma.conflict_bus(), Some(Bus::External));
+    }
+
+    #[test]
+    fn test_hdma_length() {
+        let mut dma = VramDma::new();
+        assert_eq!(dma.read_length(), 0xFF);
+        dma.write_register(0xFF55, HDMA_MODE | 0x02);
+        assert_eq!(dma.read_length(), 0x02);
+
+        // nothing happens outside HBlank or while halted
+        assert_eq!(dma.due_blocks(false), 0);
+        dma.set_hblank(true);
+        assert_eq!(dma.due_blocks(true), 0);
+        assert_eq!(dma.due_blocks(false), 1);
+        dma.next_block();
+        assert_eq!(dma.due_blocks(false), 0);
+        assert_eq!(dma.read_length(), 0x01);
+
+        dma.write_register(0xFF55, 0x00);
+        assert_eq!(dma.read_length(), 0x81);
+        dma.set_hblank(true);
+        assert_eq!(dma.due_blocks(false), 0);
+
+        dma.write_register(0xFF55, 0x03);
+        assert_eq!(dma.due_blocks(true), 4);
+        assert_eq!(dma.due_blocks(false), 0);
+
+        assert_eq!(vram_dma_stall(2, false), 64);
+        assert_eq!(vram_dma_stall(2, true), 128);
+    }
+}
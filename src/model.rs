@@ -0,0 +1,201 @@
+use std::str::FromStr;
+use crate::cartridge::CartridgeHeader;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const DMG_CLOCK_SPEED: u32 = 4_194_304;     // DMG/MGB/CGB/AGB master clock in Hz
+const SGB_CLOCK_SPEED: u32 = 4_295_454;     // SGB derives its clock from the SNES
+
+// DMG shades from lightest to darkest, as 0x00RRGGBB
+const DMG_SHADES: [u32; 4] = [0x009BBC0F, 0x008BAC0F, 0x00306230, 0x000F380F];
+const MGB_SHADES: [u32; 4] = [0x00C4CFA1, 0x008B956D, 0x004D533C, 0x001F1F1F];
+const SGB_SHADES: [u32; 4] = [0x00F8E8C8, 0x00D89048, 0x00A82820, 0x00301850];
+const GRAY_SHADES: [u32; 4] = [0x00FFFFFF, 0x00AAAAAA, 0x00555555, 0x00000000];
+
+/* ----- TYPE DECLARATIONS ----- */
+// the hardware revision being emulated
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Model {
+    Dmg,    // original Game Boy
+    Mgb,    // Game Boy Pocket / Light
+    Sgb,    // Super Game Boy
+    Sgb2,   // Super Game Boy 2
+    Cgb,    // Game Boy Color
+    Agb,    // Game Boy Advance running GB/GBC software
+}
+
+// register values left behind by the boot ROM
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BootState {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Model {
+    pub const ALL: [Model; 6] = [Model::Dmg, Model::Mgb, Model::Sgb, Model::Sgb2, Model::Cgb, Model::Agb];
+
+    // pick a model from the CGB flag at 0x0143 of the cartridge header
+    pub fn from_cgb_flag(flag: u8) -> Model {
+        if flag & 0x80 != 0 { Model::Cgb } else { Model::Dmg }
+    }
+
+    // pick a model for a ROM image; falls back to DMG if the header is missing
+    pub fn detect(rom: &[u8]) -> Model {
+        match CartridgeHeader::parse(rom) {
+            Some(header) => Model::from_cgb_flag(header.cgb_flag),
+            None => Model::Dmg,
+        }
+    }
+
+    // true for hardware with the CGB feature set (color, banking, double speed)
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    pub fn clock_speed(&self) -> u32 {
+        match self {
+            Model::Sgb => SGB_CLOCK_SPEED,
+            _ => DMG_CLOCK_SPEED,
+        }
+    }
+
+    // CPU state after the boot ROM hands over to the cartridge at 0x0100
+    // cgb_mode is only meaningful on CGB hardware: false when a DMG-only cartridge is running
+    pub fn boot_state(&self, cgb_mode: bool) -> BootState {
+        let (af, bc, de, hl) = match self {
+            Model::Dmg => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            Model::Mgb => (0xFFB0, 0x0013, 0x00D8, 0x014D),
+            Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::Sgb2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            Model::Cgb if cgb_mode => (0x1180, 0x0000, 0xFF56, 0x000D),
+            Model::Cgb => (0x1180, 0x0000, 0x0008, 0x007C),
+            Model::Agb if cgb_mode => (0x1100, 0x0100, 0xFF56, 0x000D),
+            Model::Agb => (0x1100, 0x0100, 0x0008, 0x007C),
+        };
+
+        BootState { af, bc, de, hl, sp: 0xFFFE, pc: 0x0100 }
+    }
+
+    // upper byte of the system counter when the boot ROM exits
+    pub fn boot_div(&self) -> u8 {
+        match self {
+            Model::Dmg | Model::Mgb => 0xAB,
+            _ => 0x00,
+        }
+    }
+
+    // colors used for the four DMG shades on monochrome hardware
+    pub fn shades(&self) -> [u32; 4] {
+        match self {
+            Model::Dmg => DMG_SHADES,
+            Model::Mgb => MGB_SHADES,
+            Model::Sgb | Model::Sgb2 => SGB_SHADES,
+            Model::Cgb | Model::Agb => GRAY_SHADES,
+        }
+    }
+
+    pub fn vram_banks(&self) -> usize {
+        if self.is_cgb() { 2 } else { 1 }
+    }
+
+    pub fn wram_banks(&self) -> usize {
+        if self.is_cgb() { 8 } else { 2 }
+    }
+
+    /* ----- QUIRKS ----- */
+    // writing STAT briefly enables every STAT source, which can fire a spurious interrupt
+    pub fn has_stat_write_bug(&self) -> bool {
+        !self.is_cgb()
+    }
+
+    // retriggering the wave channel while it reads wave RAM corrupts the first bytes
+    pub fn has_wave_ram_corruption(&self) -> bool {
+        !self.is_cgb()
+    }
+
+    // wave RAM is only reachable while channel 3 plays on the cycle the channel reads it
+    pub fn locks_wave_ram(&self) -> bool {
+        !self.is_cgb()
+    }
+
+    // powering the APU off leaves the length counters alone, and NRx1 stays writable
+    pub fn keeps_length_on_power_off(&self) -> bool {
+        !self.is_cgb()
+    }
+
+    // OAM DMA from 0xE000 and above reads echo RAM (CGB reads cartridge RAM instead)
+    pub fn dma_mirrors_echo_ram(&self) -> bool {
+        !self.is_cgb()
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "sgb2" => Ok(Model::Sgb2),
+            "cgb" => Ok(Model::Cgb),
+            "agb" => Ok(Model::Agb),
+            _ => Err(format!("unknown model '{}'", s)),
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_cgb_flag(flag: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0143] = flag;
+        rom
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Model::detect(&rom_with_cgb_flag(0x00)), Model::Dmg);
+        assert_eq!(Model::detect(&rom_with_cgb_flag(0x80)), Model::Cgb);
+        assert_eq!(Model::detect(&rom_with_cgb_flag(0xC0)), Model::Cgb);
+        assert_eq!(Model::detect(&[0; 0x10]), Model::Dmg);
+    }
+
+    #[test]
+    fn test_boot_state() {
+        assert_eq!(Model::Dmg.boot_state(false).af, 0x01B0);
+        assert_eq!(Model::Mgb.boot_state(false).af, 0xFFB0);
+        assert_eq!(Model::Cgb.boot_state(true).af >> 8, 0x11);
+        assert_eq!(Model::Agb.boot_state(true).bc, 0x0100);
+        for model in Model::ALL {
+            assert_eq!(model.boot_state(model.is_cgb()).sp, 0xFFFE);
+            assert_eq!(model.boot_state(model.is_cgb()).pc, 0x0100);
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("sgb2".parse::<Model>(), Ok(Model::Sgb2));
+        assert_eq!("CGB".parse::<Model>(), Ok(Model::Cgb));
+        assert!("gba".parse::<Model>().is_err());
+    }
+
+    #[test]
+    fn test_banks() {
+        assert_eq!(Model::Dmg.vram_banks(), 1);
+        assert_eq!(Model::Sgb2.wram_banks(), 2);
+        assert_eq!(Model::Cgb.vram_banks(), 2);
+        assert_eq!(Model::Agb.wram_banks(), 8);
+    }
+}
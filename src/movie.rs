@@ -0,0 +1,463 @@
+use std::fmt;
+use crate::dmgcpu::DMGCPU;
+use crate::model::Model;
+use crate::ppu::Renderer;
+use crate::state::{self, StateError, StateReader, StateWriter};
+
+mod vbm;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const MAGIC: &[u8; 8] = b"CRABMOVI";
+
+// same rules as save states: a different major version can't be played, minor versions only add
+pub const MOVIE_VERSION_MAJOR: u16 = 1;
+pub const MOVIE_VERSION_MINOR: u16 = 0;
+
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;     // frames between state hashes, once a second
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x00000100000001B3;
+
+/* ----- TYPE DECLARATIONS ----- */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MovieError {
+    NotAMovie,
+    Version { found: u16, supported: u16 },
+    Truncated,
+    Invalid(&'static str),
+    RomMismatch,
+    State(StateError),      // the movie's save state can't be loaded
+    Unsupported(String),    // an imported movie needs something crabboy doesn't do
+}
+
+// where a movie's first frame starts from
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum MovieStart {
+    PowerOn,
+    State(Vec<u8>),
+}
+
+// the buttons held on every frame from a known start, and hashes of the machine's state along
+// the way to tell whether playing it back still ends up in the same place
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Movie {
+    model: Model,
+    cartridge: Vec<u8>,     // cartridge header bytes 0x0134-0x014F, empty when unknown
+    renderer: Renderer,     // the framebuffer is part of the hashed state, so these have to match
+    color_correction: bool,
+    start: MovieStart,
+    inputs: Vec<u8>,        // Button masks, one per frame
+    hash_interval: u32,     // 0 when the movie has no hashes
+    hashes: Vec<u64>,       // the state hash after every hash_interval frames
+}
+
+// the first state hash a playback didn't match
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Desync {
+    pub frame: u64,
+    pub last_synced: u64,   // the frame of the last hash that did match, 0 for none
+    pub expected: u64,
+    pub found: u64,
+}
+
+// a movie converted from another emulator's format, with what it uses that crabboy doesn't
+// emulate and so may make it desync
+pub struct Import {
+    pub movie: Movie,
+    pub unsupported: Vec<String>,
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: u64,
+    last_synced: u64,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::Version { found, supported } => {
+                write!(f, "movie format version {} is not supported, this build reads version {}", found, supported)
+            },
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Invalid(what) => write!(f, "movie has an invalid {}", what),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::State(error) => write!(f, "movie's start state failed to load: {}", error),
+            MovieError::Unsupported(what) => write!(f, "movie needs {}, which isn't supported", what),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// movies share the save state format's field encoding, whose errors mean the movie is broken
+impl From<StateError> for MovieError {
+    fn from(error: StateError) -> Self {
+        match error {
+            StateError::Truncated => MovieError::Truncated,
+            StateError::Invalid(what) => MovieError::Invalid(what),
+            error => MovieError::State(error),
+        }
+    }
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "movie desynced by frame {} (in sync at frame {}), state hash {:016x} instead of {:016x}",
+            self.frame, self.last_synced, self.found, self.expected)
+    }
+}
+
+impl std::error::Error for Desync {}
+
+impl Movie {
+    // a movie without hashes, for inputs that come from somewhere other than a recording
+    pub fn new(model: Model, cartridge: &[u8], start: MovieStart, inputs: Vec<u8>) -> Movie {
+        Movie {
+            model,
+            cartridge: cartridge.to_vec(),
+            renderer: Renderer::Scanline,
+            color_correction: false,
+            start,
+            inputs,
+            hash_interval: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    pub fn get_cartridge(&self) -> &[u8] {
+        &self.cartridge
+    }
+
+    pub fn get_renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    pub fn is_color_correction_enabled(&self) -> bool {
+        self.color_correction
+    }
+
+    pub fn get_start(&self) -> &MovieStart {
+        &self.start
+    }
+
+    pub fn get_inputs(&self) -> &[u8] {
+        &self.inputs
+    }
+
+    pub fn get_hash_interval(&self) -> u32 {
+        self.hash_interval
+    }
+
+    pub fn get_hashes(&self) -> &[u64] {
+        &self.hashes
+    }
+
+    // length in frames
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // the machine the movie plays back on: a new one at power-on with the ROM loaded, or one
+    // restored from the movie's save state, rendering the way the recording did
+    pub fn start(&self, rom: &[u8]) -> Result<DMGCPU, MovieError> {
+        let mut cpu = DMGCPU::with_model(self.model);
+        cpu.load_rom(rom);
+        cpu.set_renderer(self.renderer);
+        cpu.set_color_correction(self.color_correction);
+        if !self.cartridge.is_empty() && cpu.get_memory().get_cartridge_header() != self.cartridge {
+            return Err(MovieError::RomMismatch);
+        }
+        if let MovieStart::State(data) = &self.start {
+            cpu.load_state(data).map_err(MovieError::State)?;
+        }
+        Ok(cpu)
+    }
+
+    // convert a movie from another emulator, recorded on the given ROM. VisualBoyAdvance's .vbm
+    // is the only format so far
+    pub fn import(data: &[u8], rom: &[u8]) -> Result<Import, MovieError> {
+        if data.starts_with(vbm::SIGNATURE) {
+            vbm::import(data, rom)
+        } else {
+            Err(MovieError::NotAMovie)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut movie = StateWriter::new();
+        movie.write_bytes(MAGIC);
+        movie.write_u16(MOVIE_VERSION_MAJOR);
+        movie.write_u16(MOVIE_VERSION_MINOR);
+        movie.section(b"INFO", |movie| {
+            movie.write_u8(state::model_id(self.model));
+            movie.write_vec(&self.cartridge);
+            movie.write_u32(self.hash_interval);
+            movie.write_bool(self.renderer == Renderer::PixelFifo);
+            movie.write_bool(self.color_correction);
+        });
+        movie.section(b"STRT", |movie| match &self.start {
+            MovieStart::PowerOn => movie.write_u8(0),
+            MovieStart::State(data) => {
+                movie.write_u8(1);
+                movie.write_vec(data);
+            },
+        });
+        movie.section(b"INPT", |movie| movie.write_vec(&self.inputs));
+        movie.section(b"HASH", |movie| {
+            movie.write_u32(self.hashes.len() as u32);
+            for &hash in &self.hashes {
+                movie.write_u64(hash);
+            }
+        });
+        movie.into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 8];
+        if reader.read_bytes(&mut magic).is_err() || &magic != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let major = reader.read_u16()?;
+        if major != MOVIE_VERSION_MAJOR {
+            return Err(MovieError::Version { found: major, supported: MOVIE_VERSION_MAJOR });
+        }
+        reader.read_u16()?;
+
+        let mut info = None;
+        let mut start = None;
+        let mut inputs = None;
+        let mut hashes = Vec::new();
+        while let Some((tag, mut section)) = reader.next_section()? {
+            match &tag {
+                b"INFO" => {
+                    let model = *Model::ALL.get(section.read_u8()? as usize).ok_or(MovieError::Invalid("model"))?;
+                    let cartridge = section.read_vec()?;
+                    let hash_interval = section.read_u32()?;
+                    let renderer = if section.read_bool()? { Renderer::PixelFifo } else { Renderer::Scanline };
+                    info = Some((model, cartridge, hash_interval, renderer, section.read_bool()?));
+                },
+                b"STRT" => {
+                    start = Some(match section.read_u8()? {
+                        0 => MovieStart::PowerOn,
+                        1 => MovieStart::State(section.read_vec()?),
+                        _ => return Err(MovieError::Invalid("start")),
+                    });
+                },
+                b"INPT" => inputs = Some(section.read_vec()?),
+                b"HASH" => {
+                    let count = section.read_u32()?;
+                    hashes = (0..count).map(|_| section.read_u64()).collect::<Result<_, _>>()?;
+                },
+                _ => {},
+            }
+        }
+
+        let (model, cartridge, hash_interval, renderer, color_correction) = info.ok_or(MovieError::Invalid("INFO section"))?;
+        Ok(Movie {
+            model,
+            cartridge,
+            renderer,
+            color_correction,
+            start: start.ok_or(MovieError::Invalid("STRT section"))?,
+            inputs: inputs.ok_or(MovieError::Invalid("INPT section"))?,
+            hash_interval,
+            hashes,
+        })
+    }
+}
+
+impl MovieRecorder {
+    // record from a machine nothing has run on yet, since power-on. panics if it has run
+    pub fn from_power_on(cpu: &DMGCPU, hash_interval: u32) -> MovieRecorder {
+        assert_eq!(*cpu.get_cycle_count(), 0, "the machine has run since power-on");
+        MovieRecorder::build(cpu, MovieStart::PowerOn, hash_interval)
+    }
+
+    // record from a save state of the machine as it is now
+    pub fn from_state(cpu: &DMGCPU, hash_interval: u32) -> MovieRecorder {
+        MovieRecorder::build(cpu, MovieStart::State(cpu.save_state()), hash_interval)
+    }
+
+    pub fn get_movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+
+    // run one frame with the given buttons held
+    pub fn record_frame(&mut self, cpu: &mut DMGCPU, buttons: u8) {
+        cpu.set_buttons(buttons);
+        cpu.run_frame();
+        self.movie.inputs.push(buttons);
+        if self.movie.inputs.len().is_multiple_of(self.movie.hash_interval as usize) {
+            self.movie.hashes.push(state_hash(cpu));
+        }
+    }
+
+    /* ----- PRIVATE ----- */
+    fn build(cpu: &DMGCPU, start: MovieStart, hash_interval: u32) -> MovieRecorder {
+        let mut movie = Movie::new(cpu.get_model(), cpu.get_memory().get_cartridge_header(), start, Vec::new());
+        movie.renderer = cpu.get_renderer();
+        movie.color_correction = cpu.is_color_correction_enabled();
+        movie.hash_interval = hash_interval.max(1);
+        MovieRecorder { movie }
+    }
+}
+
+impl MoviePlayer {
+    // play on a machine from Movie::start
+    pub fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer {
+            movie,
+            frame: 0,
+            last_synced: 0,
+        }
+    }
+
+    pub fn get_movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // frames played so far
+    pub fn get_frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame as usize >= self.movie.inputs.len()
+    }
+
+    // play the next frame, checking the state against the movie's hash when there is one for
+    // it. false once the movie is over, without running anything
+    pub fn step(&mut self, cpu: &mut DMGCPU) -> Result<bool, Desync> {
+        let Some(&buttons) = self.movie.inputs.get(self.frame as usize) else {
+            return Ok(false);
+        };
+        cpu.set_buttons(buttons);
+        cpu.run_frame();
+        self.frame += 1;
+
+        let interval = self.movie.hash_interval as u64;
+        if interval != 0 && self.frame.is_multiple_of(interval) {
+            if let Some(&expected) = self.movie.hashes.get((self.frame / interval - 1) as usize) {
+                let found = state_hash(cpu);
+                if found != expected {
+                    return Err(Desync { frame: self.frame, last_synced: self.last_synced, expected, found });
+                }
+                self.last_synced = self.frame;
+            }
+        }
+        Ok(true)
+    }
+
+    // play the rest of the movie, stopping at the first desync
+    pub fn play(&mut self, cpu: &mut DMGCPU) -> Result<u64, Desync> {
+        while self.step(cpu)? {}
+        Ok(self.frame)
+    }
+}
+
+// FNV-1a of the machine's save state
+pub fn state_hash(cpu: &DMGCPU) -> u64 {
+    cpu.save_state().iter().fold(FNV_OFFSET_BASIS, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::Button;
+
+    // halts straight away, the held buttons still end up in the joypad's state
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x76;
+        rom
+    }
+
+    fn record(rom: &[u8]) -> Movie {
+        let mut cpu = DMGCPU::from_rom(rom);
+        let mut recorder = MovieRecorder::from_power_on(&cpu, 2);
+        for frame in 0..7 {
+            recorder.record_frame(&mut cpu, if frame == 3 { Button::Start as u8 } else { 0 });
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let movie = record(&rom());
+        assert_eq!(movie.len(), 7);
+        assert_eq!(movie.get_hashes().len(), 3);
+        assert_eq!(Movie::parse(&movie.to_bytes()), Ok(movie.clone()));
+
+        assert_eq!(Movie::parse(b"CRABSAVE"), Err(MovieError::NotAMovie));
+        let mut newer = movie.to_bytes();
+        newer[8] += 1;
+        assert_eq!(Movie::parse(&newer), Err(MovieError::Version { found: 2, supported: MOVIE_VERSION_MAJOR }));
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::parse(&bytes[..bytes.len() - 3]), Err(MovieError::Truncated));
+    }
+
+    #[test]
+    fn test_playback() {
+        let rom = rom();
+        let movie = record(&rom);
+        let mut cpu = movie.start(&rom).unwrap();
+        let mut player = MoviePlayer::new(movie.clone());
+        assert_eq!(player.play(&mut cpu), Ok(7));
+        assert!(player.is_finished());
+        assert_eq!(player.step(&mut cpu), Ok(false));
+
+        // from a save state part way in, played back with the renderer it was recorded with
+        let mut cpu = DMGCPU::from_rom(&rom);
+        cpu.set_renderer(Renderer::PixelFifo);
+        cpu.set_color_correction(true);
+        cpu.run_frame();
+        let mut recorder = MovieRecorder::from_state(&cpu, 1);
+        recorder.record_frame(&mut cpu, Button::A as u8);
+        let movie = Movie::parse(&recorder.finish().to_bytes()).unwrap();
+        let mut cpu = movie.start(&rom).unwrap();
+        assert_eq!(cpu.get_renderer(), Renderer::PixelFifo);
+        assert!(cpu.is_color_correction_enabled());
+        assert_eq!(MoviePlayer::new(movie).play(&mut cpu), Ok(1));
+
+        let mut other = rom.clone();
+        other[0x0134] = b'X';
+        assert_eq!(record(&rom).start(&other).err(), Some(MovieError::RomMismatch));
+    }
+
+    #[test]
+    fn test_desync() {
+        let rom = rom();
+        let movie = record(&rom);
+        let mut inputs = movie.get_inputs().to_vec();
+        inputs[3] = 0;
+        let mut edited = Movie::new(movie.get_model(), movie.get_cartridge(), MovieStart::PowerOn, inputs);
+        edited.hash_interval = movie.get_hash_interval();
+        edited.hashes = movie.get_hashes().to_vec();
+
+        let mut cpu = edited.start(&rom).unwrap();
+        let desync = MoviePlayer::new(edited).play(&mut cpu).unwrap_err();
+        assert_eq!((desync.frame, desync.last_synced), (4, 2));
+        assert!(desync.to_string().contains("desynced by frame 4"));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::model::Model;
+use super::{Import, Movie, MovieError, MovieStart};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+pub(super) const SIGNATURE: &[u8; 4] = b"VBM\x1A";
+
+const HEADER_SIZE: usize = 0x100;
+const VERSION: u32 = 1;
+
+// start flags
+const START_SNAPSHOT: u8 = 0x01;
+const START_SRAM: u8 = 0x02;
+
+// system flags, used when the emulator type is left on automatic
+const SYSTEM_GBA: u8 = 0x01;
+const SYSTEM_GBC: u8 = 0x02;
+const SYSTEM_SGB: u8 = 0x04;
+
+// emulator option flags
+const OPTION_BIOS: u8 = 0x01;
+const OPTION_RTC: u8 = 0x04;
+
+// controller bits beyond the eight buttons
+const INPUT_BUTTONS: u16 = 0x00FF;
+const INPUT_SHOULDERS: u16 = 0x0300;
+const INPUT_RESET: u16 = 0x0C00;
+const INPUT_MOTION: u16 = 0xF000;
+
+/* ----- IMPL DEFINITIONS ----- */
+// VisualBoyAdvance movie: a 256 byte header followed by two bytes of buttons per controller per
+// frame. only power-on movies convert, a VBA snapshot has nothing in common with a save state
+pub(super) fn import(data: &[u8], rom: &[u8]) -> Result<Import, MovieError> {
+    if data.len() < HEADER_SIZE {
+        return Err(MovieError::Truncated);
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let version = u32_at(0x04);
+    if version != VERSION {
+        return Err(MovieError::Version { found: version as u16, supported: VERSION as u16 });
+    }
+    let frames = u32_at(0x0C) as usize;
+    let start = data[0x14];
+    let controllers = data[0x15];
+    let system = data[0x16];
+    let options = data[0x17];
+    let input_offset = u32_at(0x3C) as usize;
+
+    if start & START_SNAPSHOT != 0 {
+        return Err(MovieError::Unsupported(String::from("starting from a VBA snapshot")));
+    }
+    let model = match u32_at(0x20) {
+        1 => Model::Cgb,
+        2 => Model::Sgb,
+        3 => Model::Dmg,
+        4 => Model::Agb,
+        5 => Model::Sgb2,
+        _ if system & SYSTEM_GBA != 0 => return Err(MovieError::Unsupported(String::from("Game Boy Advance games"))),
+        _ if system & SYSTEM_GBC != 0 => Model::Cgb,
+        _ if system & SYSTEM_SGB != 0 => Model::Sgb,
+        _ => Model::detect(rom),
+    };
+
+    // VBA keeps the title and the header checksum of the ROM it recorded with
+    let cartridge = rom.get(0x0134..0x0150).ok_or(MovieError::RomMismatch)?;
+    if cartridge[..12] != data[0x24..0x30] || cartridge[0x19] != data[0x31] {
+        return Err(MovieError::RomMismatch);
+    }
+
+    let mut unsupported = Vec::new();
+    if start & START_SRAM != 0 {
+        unsupported.push(String::from("starting from saved cartridge RAM, the movie starts with it cleared"));
+    }
+    if options & OPTION_BIOS != 0 {
+        unsupported.push(String::from("running the boot ROM, the movie starts where it hands over"));
+    }
+    if options & OPTION_RTC != 0 {
+        unsupported.push(String::from("the cartridge real-time clock"));
+    }
+    if controllers & !0x01 != 0 {
+        unsupported.push(String::from("controllers 2 to 4, only the first one is played"));
+    }
+
+    let stride = 2 * (controllers.count_ones() as usize).max(1);
+    let end = frames.checked_mul(stride).and_then(|size| size.checked_add(input_offset)).ok_or(MovieError::Truncated)?;
+    let input = data.get(input_offset..end).ok_or(MovieError::Truncated)?;
+    let mut inputs = Vec::with_capacity(frames);
+    let mut resets = Vec::new();
+    let mut extra = 0;
+    for (frame, bytes) in input.chunks_exact(stride).enumerate() {
+        let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        if value & INPUT_RESET != 0 {
+            resets.push(frame);
+        }
+        extra |= value & (INPUT_SHOULDERS | INPUT_MOTION);
+        inputs.push(convert_buttons((value & INPUT_BUTTONS) as u8));
+    }
+    if let Some(frame) = resets.first() {
+        unsupported.push(format!("resetting during the movie, on {} frames from frame {}", resets.len(), frame));
+    }
+    if extra & INPUT_SHOULDERS != 0 {
+        unsupported.push(String::from("the L and R buttons, which a Game Boy doesn't have"));
+    }
+    if extra & INPUT_MOTION != 0 {
+        unsupported.push(String::from("the motion sensor"));
+    }
+
+    Ok(Import {
+        movie: Movie::new(model, cartridge, MovieStart::PowerOn, inputs),
+        unsupported,
+    })
+}
+
+// VBA's A, B, Select, Start, Right, Left, Up, Down to Button bits
+fn convert_buttons(value: u8) -> u8 {
+    value.rotate_left(4)
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::Button;
+
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x76;
+        rom[0x0134..0x013B].copy_from_slice(b"CRABTAS");
+        rom[0x014D] = (0x0134..0x014D).fold(0u8, |sum: u8, i| sum.wrapping_sub(rom[i]).wrapping_sub(1));
+        rom
+    }
+
+    fn vbm(rom: &[u8], inputs: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0x00..0x04].copy_from_slice(SIGNATURE);
+        data[0x04..0x08].copy_from_slice(&VERSION.to_le_bytes());
+        data[0x0C..0x10].copy_from_slice(&(inputs.len() as u32).to_le_bytes());
+        data[0x15] = 0x01;
+        data[0x20..0x24].copy_from_slice(&3u32.to_le_bytes());
+        data[0x24..0x30].copy_from_slice(&rom[0x0134..0x0140]);
+        data[0x31] = rom[0x014D];
+        data[0x3C..0x40].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        for input in inputs {
+            data.extend_from_slice(&input.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_import() {
+        let rom = rom();
+        let import = Movie::import(&vbm(&rom, &[0x0000, 0x0001, 0x0088, 0x0000]), &rom).unwrap();
+        assert!(import.unsupported.is_empty());
+        assert_eq!(import.movie.get_model(), Model::Dmg);
+        assert_eq!(import.movie.get_inputs(), &[0, Button::A as u8, Button::Start as u8 | Button::Down as u8, 0]);
+        assert!(import.movie.start(&rom).is_ok());
+
+        let mut other = rom.clone();
+        other[0x0134] = b'X';
+        assert_eq!(Movie::import(&vbm(&rom, &[0]), &other).err(), Some(MovieError::RomMismatch));
+        let data = vbm(&rom, &[0, 0]);
+        assert_eq!(Movie::import(&data[..data.len() - 1], &rom).err(), Some(MovieError::Truncated));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let rom = rom();
+        let mut data = vbm(&rom, &[0x0000, 0x0800, 0x0100]);
+        data[0x14] = START_SRAM;
+        data[0x17] = OPTION_RTC;
+        let import = Movie::import(&data, &rom).unwrap();
+        assert_eq!(import.unsupported.len(), 4);
+        assert!(import.unsupported[2].contains("on 1 frames from frame 1"));
+        assert_eq!(import.movie.len(), 3);
+
+        data[0x14] = START_SNAPSHOT;
+        assert!(matches!(Movie::import(&data, &rom), Err(MovieError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,1276 @@
+use std::str::FromStr;
+use crate::memory::{INTERRUPT_VBLANK, INTERRUPT_STAT};
+use crate::model::Model;
+use crate::state::{StateError, StateReader, StateWriter};
+use self::palette::{PaletteRam, rgb555_to_rgb888};
+use self::pixel_fifo::PixelFifo;
+
+mod palette;
+mod pixel_fifo;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const FRAME_DOTS: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const VBLANK_START_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+const VRAM_BANK_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+
+const LCDC_ENABLE: u8 = 0x80;
+const LCDC_WINDOW_MAP: u8 = 0x40;
+const LCDC_WINDOW_ENABLE: u8 = 0x20;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_BG_MAP: u8 = 0x08;
+const LCDC_OBJ_SIZE: u8 = 0x04;
+const LCDC_OBJ_ENABLE: u8 = 0x02;
+const LCDC_BG_ENABLE: u8 = 0x01;
+
+const OBJ_BG_PRIORITY: u8 = 0x80;
+const OBJ_Y_FLIP: u8 = 0x40;
+const OBJ_X_FLIP: u8 = 0x20;
+const OBJ_PALETTE: u8 = 0x10;
+const OBJS_PER_LINE: usize = 10;
+
+// CGB tile attributes in VRAM bank 1 share the flip and priority bits with OAM flags
+const ATTR_PRIORITY: u8 = 0x80;
+const ATTR_BANK: u8 = 0x08;
+const ATTR_PALETTE: u8 = 0x07;
+
+// palettes the CGB boot ROM assigns to cartridges it does not recognize
+const COMPAT_BG_PALETTE: [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+const COMPAT_OBJ_PALETTE: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+
+const BG_MAP_LOW: usize = 0x1800;
+const BG_MAP_HIGH: usize = 0x1C00;
+
+const STAT_LYC_INTERRUPT: u8 = 0x40;
+const STAT_OAM_INTERRUPT: u8 = 0x20;
+const STAT_VBLANK_INTERRUPT: u8 = 0x10;
+const STAT_HBLANK_INTERRUPT: u8 = 0x08;
+const STAT_LYC_EQUAL: u8 = 0x04;
+const STAT_WRITABLE: u8 = 0x78;
+
+/* ----- TYPE DECLARATIONS ----- */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+// how mode 3 turns VRAM into pixels
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Renderer {
+    Scanline,   // whole line at once with a fixed mode 3 length, fast
+    PixelFifo,  // dot by dot through the fetcher and pixel FIFOs, honors mid-line register writes
+}
+
+// an OAM entry selected during mode 2, positions already converted to screen space
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Sprite {
+    x: i16,
+    y: i16,
+    tile: u8,
+    flags: u8,
+    index: u8,
+}
+
+pub struct Ppu {
+    model: Model,
+    cgb_mode: bool,
+    vram: Vec<u8>,
+    vram_bank: u8,
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    stat: u8,           // only the interrupt enable bits, the rest is derived
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    line: u8,           // scanline being processed, LY can differ from it on line 153
+    dot: u16,           // position within the current line
+    stat_line: bool,    // combined STAT interrupt signal, the interrupt fires on its rising edge
+    interrupts: u8,     // interrupt requests not yet collected by the bus
+    window_triggered: bool, // WY matched LY at some point this frame
+    window_line: u8,    // internal line counter, only advances on lines where the window was drawn
+    bg_line: [u8; SCREEN_WIDTH],    // color indices of the current line before palette mapping
+    bg_attr_line: [u8; SCREEN_WIDTH],   // CGB tile attributes for each pixel of bg_line
+    line_sprites: Vec<Sprite>,      // up to 10 objects found by the OAM scan, in drawing priority
+    framebuffer: Vec<u32>,
+    frame_count: u64,
+    renderer: Renderer,
+    line_renderer: Renderer,        // renderer chosen when the current line entered mode 3
+    fifo: PixelFifo,
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
+    color_correction: bool,
+    opri: u8,   // bit 0 set selects DMG-style object priority by X coordinate
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Ppu {
+    pub fn new(model: Model) -> Ppu {
+        Ppu {
+            model,
+            cgb_mode: false,
+            vram: vec![0; VRAM_BANK_SIZE * model.vram_banks()],
+            vram_bank: 0,
+            oam: [0; OAM_SIZE],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+            line: 0,
+            dot: 0,
+            stat_line: false,
+            interrupts: 0,
+            window_triggered: false,
+            window_line: 0,
+            bg_line: [0; SCREEN_WIDTH],
+            bg_attr_line: [0; SCREEN_WIDTH],
+            line_sprites: Vec::with_capacity(OBJS_PER_LINE),
+            framebuffer: vec![model.shades()[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_count: 0,
+            renderer: Renderer::Scanline,
+            line_renderer: Renderer::Scanline,
+            fifo: PixelFifo::new(),
+            bg_palettes: PaletteRam::new(),
+            obj_palettes: PaletteRam::new(),
+            color_correction: false,
+            opri: 0,
+        }
+    }
+
+    // register values after the boot ROM has shown the logo
+    pub fn boot(&mut self) {
+        self.write_register(0xFF40, 0x91);
+        self.write_register(0xFF47, 0xFC);
+        self.interrupts = 0;
+
+        if self.model.is_cgb() {
+            self.opri = if self.cgb_mode { 0 } else { 1 };
+
+            // CGB games start with white backgrounds, DMG games get the default colorization
+            for palette in 0..8 {
+                for color in 0..4 {
+                    let (bg, obj) = if self.cgb_mode {
+                        (0x7FFF, 0x0000)
+                    } else {
+                        (COMPAT_BG_PALETTE[color], COMPAT_OBJ_PALETTE[color])
+                    };
+                    self.bg_palettes.set_color(palette, color as u8, bg);
+                    self.obj_palettes.set_color(palette, color as u8, obj);
+                }
+            }
+        }
+    }
+
+    // CGB hardware running a cartridge with CGB support, see Memory::is_cgb_mode
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode && self.model.is_cgb();
+    }
+
+    // run CGB colors through the LCD response curve instead of scaling them linearly
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.color_correction = enabled;
+    }
+
+    pub fn is_color_correction_enabled(&self) -> bool {
+        self.color_correction
+    }
+
+    pub fn get_mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn get_ly(&self) -> u8 {
+        self.ly
+    }
+
+    // finished frames as 0x00RRGGBB pixels, row by row
+    pub fn get_framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    // number of frames completed, increments when VBlank starts
+    pub fn get_frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn get_renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    // takes effect from the next line
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn is_lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_ENABLE != 0
+    }
+
+    // advance by the given number of dots and return the interrupts requested meanwhile
+    pub fn tick(&mut self, dots: u8) -> u8 {
+        if self.is_lcd_enabled() {
+            for _ in 0..dots {
+                self.step();
+            }
+        }
+
+        let interrupts = self.interrupts;
+        self.interrupts = 0;
+        interrupts
+    }
+
+    /* ----- BUS ACCESS ----- */
+    pub fn read_vram(&self, address: u16) -> u8 {
+        if self.vram_blocked() {
+            return 0xFF;
+        }
+        self.vram[self.vram_index(address)]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        if !self.vram_blocked() {
+            let index = self.vram_index(address);
+            self.vram[index] = value;
+        }
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8 {
+        if self.oam_blocked() {
+            return 0xFF;
+        }
+        self.oam[(address as usize) & 0xFF]
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        if !self.oam_blocked() {
+            self.oam[(address as usize) & 0xFF] = value;
+        }
+    }
+
+    // OAM DMA writes go through whatever mode the PPU is in
+    pub fn dma_write_oam(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => {
+                let mode = if self.is_lcd_enabled() { self.mode as u8 } else { 0 };
+                let coincidence = if self.ly == self.lyc { STAT_LYC_EQUAL } else { 0 };
+                0x80 | self.stat | coincidence | mode
+            },
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb_mode => 0xFE | self.vram_bank,
+            0xFF68 if self.cgb_mode => self.bg_palettes.read_spec(),
+            0xFF69 if self.cgb_mode && !self.palettes_blocked() => self.bg_palettes.read_data(),
+            0xFF6A if self.cgb_mode => self.obj_palettes.read_spec(),
+            0xFF6B if self.cgb_mode && !self.palettes_blocked() => self.obj_palettes.read_data(),
+            0xFF6C if self.model.is_cgb() => 0xFE | self.opri,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF40 => {
+                let was_enabled = self.is_lcd_enabled();
+                self.lcdc = value;
+                if was_enabled && !self.is_lcd_enabled() {
+                    // LY and the mode read as zero while the LCD is off
+                    self.line = 0;
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.mode = Mode::HBlank;
+                    self.stat_line = false;
+                } else if !was_enabled && self.is_lcd_enabled() {
+                    self.window_triggered = false;
+                    self.window_line = 0;
+                    self.enter_mode(Mode::OamScan);
+                    self.update_stat_line();
+                }
+            },
+            0xFF41 => {
+                // on DMG every source is enabled for one cycle during the write
+                if self.model.has_stat_write_bug() && self.is_lcd_enabled() {
+                    self.stat = STAT_WRITABLE;
+                    self.update_stat_line();
+                }
+                self.stat = value & STAT_WRITABLE;
+                if self.is_lcd_enabled() {
+                    self.update_stat_line();
+                }
+            },
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF44 => {},   // read only
+            0xFF45 => {
+                self.lyc = value;
+                if self.is_lcd_enabled() {
+                    self.update_stat_line();
+                }
+            },
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            0xFF4F if self.cgb_mode => self.vram_bank = value & 0x01,
+            0xFF68 if self.cgb_mode => self.bg_palettes.write_spec(value),
+            0xFF69 if self.cgb_mode => {
+                // blocked writes still advance the address
+                if self.palettes_blocked() {
+                    let data = self.bg_palettes.read_data();
+                    self.bg_palettes.write_data(data);
+                } else {
+                    self.bg_palettes.write_data(value);
+                }
+            },
+            0xFF6A if self.cgb_mode => self.obj_palettes.write_spec(value),
+            0xFF6B if self.cgb_mode => {
+                if self.palettes_blocked() {
+                    let data = self.obj_palettes.read_data();
+                    self.obj_palettes.write_data(data);
+                } else {
+                    self.obj_palettes.write_data(value);
+                }
+            },
+            0xFF6C if self.model.is_cgb() => self.opri = value & 0x01,
+            _ => {},
+        }
+    }
+
+    // everything but the host's choice of renderer and color correction
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.cgb_mode);
+        state.write_vec(&self.vram);
+        state.write_u8(self.vram_bank);
+        state.write_bytes(&self.oam);
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc,
+            self.bgp, self.obp0, self.obp1, self.wy, self.wx, self.opri] {
+            state.write_u8(register);
+        }
+        state.write_u8(self.mode as u8);
+        state.write_u8(self.line);
+        state.write_u16(self.dot);
+        state.write_bool(self.stat_line);
+        state.write_u8(self.interrupts);
+        state.write_bool(self.window_triggered);
+        state.write_u8(self.window_line);
+        state.write_bytes(&self.bg_line);
+        state.write_bytes(&self.bg_attr_line);
+        state.write_u8(self.line_sprites.len() as u8);
+        for sprite in &self.line_sprites {
+            state.write_u16(sprite.x as u16);
+            state.write_u16(sprite.y as u16);
+            state.write_u8(sprite.tile);
+            state.write_u8(sprite.flags);
+            state.write_u8(sprite.index);
+        }
+        for pixel in &self.framebuffer {
+            state.write_u32(*pixel);
+        }
+        state.write_u64(self.frame_count);
+        state.write_bool(self.line_renderer == Renderer::PixelFifo);
+        self.fifo.save_state(state);
+        self.bg_palettes.save_state(state);
+        self.obj_palettes.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cgb_mode = state.read_bool()?;
+        let vram = state.read_vec()?;
+        if vram.len() != self.vram.len() {
+            return Err(StateError::Invalid("VRAM size"));
+        }
+        self.vram = vram;
+        self.vram_bank = state.read_u8()? & (self.model.vram_banks() as u8 - 1);
+        state.read_bytes(&mut self.oam)?;
+        for register in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly,
+            &mut self.lyc, &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx,
+            &mut self.opri] {
+            *register = state.read_u8()?;
+        }
+        self.mode = match state.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            _ => return Err(StateError::Invalid("PPU mode")),
+        };
+        self.line = state.read_u8()?;
+        self.dot = state.read_u16()?;
+        self.stat_line = state.read_bool()?;
+        self.interrupts = state.read_u8()?;
+        self.window_triggered = state.read_bool()?;
+        self.window_line = state.read_u8()?;
+        state.read_bytes(&mut self.bg_line)?;
+        state.read_bytes(&mut self.bg_attr_line)?;
+        self.line_sprites.clear();
+        for _ in 0..state.read_u8()? {
+            self.line_sprites.push(Sprite {
+                x: state.read_u16()? as i16,
+                y: state.read_u16()? as i16,
+                tile: state.read_u8()?,
+                flags: state.read_u8()?,
+                index: state.read_u8()?,
+            });
+        }
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = state.read_u32()?;
+        }
+        self.frame_count = state.read_u64()?;
+        self.line_renderer = if state.read_bool()? { Renderer::PixelFifo } else { Renderer::Scanline };
+        self.fifo.load_state(state)?;
+        self.bg_palettes.load_state(state)?;
+        self.obj_palettes.load_state(state)
+    }
+
+    /* ----- PRIVATE ----- */
+    fn vram_index(&self, address: u16) -> usize {
+        self.vram_bank as usize * VRAM_BANK_SIZE + ((address as usize) & (VRAM_BANK_SIZE - 1))
+    }
+
+    fn palettes_blocked(&self) -> bool {
+        self.vram_blocked()
+    }
+
+    fn vram_blocked(&self) -> bool {
+        self.is_lcd_enabled() && self.mode == Mode::Drawing
+    }
+
+    fn oam_blocked(&self) -> bool {
+        self.is_lcd_enabled() && matches!(self.mode, Mode::OamScan | Mode::Drawing)
+    }
+
+    // advance one dot through modes 2 -> 3 -> 0 for lines 0-143, then mode 1 for lines 144-153
+    fn step(&mut self) {
+        self.dot += 1;
+
+        match self.mode {
+            Mode::OamScan if self.dot == OAM_SCAN_DOTS => self.enter_mode(Mode::Drawing),
+            Mode::Drawing => {
+                let done = match self.line_renderer {
+                    Renderer::Scanline => self.dot == OAM_SCAN_DOTS + DRAWING_DOTS,
+                    Renderer::PixelFifo => self.fifo_dot(),
+                };
+                if done {
+                    self.enter_mode(Mode::HBlank);
+                }
+            },
+            _ => {},
+        }
+
+        // LY already reads 0 a few dots into line 153
+        if self.line == LINES_PER_FRAME - 1 && self.dot == 4 {
+            self.ly = 0;
+        }
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.line += 1;
+            if self.line == LINES_PER_FRAME {
+                self.line = 0;
+            }
+            self.ly = self.line;
+
+            if self.line == VBLANK_START_LINE {
+                self.enter_mode(Mode::VBlank);
+                self.interrupts |= INTERRUPT_VBLANK;
+            } else if self.line < VBLANK_START_LINE {
+                self.enter_mode(Mode::OamScan);
+            }
+        }
+
+        self.update_stat_line();
+    }
+
+    fn enter_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+
+        match mode {
+            Mode::OamScan => {
+                if self.ly == self.wy {
+                    self.window_triggered = true;
+                }
+            },
+            Mode::Drawing => {
+                self.scan_oam();
+                self.line_renderer = self.renderer;
+                if self.line_renderer == Renderer::PixelFifo {
+                    self.fifo_start_line();
+                }
+            },
+            Mode::HBlank => {
+                if self.line_renderer == Renderer::Scanline {
+                    self.render_line();
+                }
+            },
+            Mode::VBlank => {
+                self.window_triggered = false;
+                self.window_line = 0;
+                self.frame_count += 1;
+            },
+        }
+    }
+
+    // select the first 10 objects in OAM order that overlap the current line
+    fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        let line = self.line as i16;
+
+        self.line_sprites.clear();
+        for (index, entry) in self.oam.chunks_exact(4).enumerate() {
+            let y = entry[0] as i16 - 16;
+            if line >= y && line < y + height {
+                self.line_sprites.push(Sprite {
+                    y,
+                    x: entry[1] as i16 - 8,
+                    tile: entry[2],
+                    flags: entry[3],
+                    index: index as u8,
+                });
+                if self.line_sprites.len() == OBJS_PER_LINE {
+                    break;
+                }
+            }
+        }
+
+        // objects are fetched left to right, sprite_priority decides which one is drawn on top
+        self.line_sprites.sort_by_key(|sprite| (sprite.x, sprite.index));
+    }
+
+    // lower wins: on DMG the object with the smaller X, ties going to the lower OAM index,
+    // in CGB mode the OAM index alone unless OPRI asks for the DMG order
+    fn sprite_priority(&self, sprite: &Sprite) -> u16 {
+        if self.cgb_mode && self.opri & 0x01 == 0 {
+            sprite.index as u16
+        } else {
+            (((sprite.x + 8) as u16) << 8) | sprite.index as u16
+        }
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 }
+    }
+
+    /* ----- RENDERING ----- */
+    fn render_line(&mut self) {
+        let row = self.line as usize * SCREEN_WIDTH;
+        let window_visible = self.window_visible();
+        let mut window_drawn = false;
+
+        for x in 0..SCREEN_WIDTH {
+            // outside CGB mode clearing LCDC bit 0 blanks both background and window
+            let (color, attributes) = if !self.cgb_mode && self.lcdc & LCDC_BG_ENABLE == 0 {
+                (0, 0)
+            } else if window_visible && x + 7 >= self.wx as usize {
+                window_drawn = true;
+                let window_x = (x + 7 - self.wx as usize) as u8;
+                self.tile_pixel(self.lcdc & LCDC_WINDOW_MAP != 0, window_x, self.window_line)
+            } else {
+                let bg_x = (x as u8).wrapping_add(self.scx);
+                let bg_y = self.line.wrapping_add(self.scy);
+                self.tile_pixel(self.lcdc & LCDC_BG_MAP != 0, bg_x, bg_y)
+            };
+            self.bg_line[x] = color;
+            self.bg_attr_line[x] = attributes;
+        }
+
+        if window_drawn {
+            self.window_line += 1;
+        }
+
+        for x in 0..SCREEN_WIDTH {
+            let sprite = if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+                self.sprite_pixel(x as i16)
+            } else {
+                None
+            };
+            self.framebuffer[row + x] = self.mix_pixel(self.bg_line[x], self.bg_attr_line[x], sprite);
+        }
+    }
+
+    fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.window_triggered && self.wx <= 166
+    }
+
+    // final color of a pixel from its background color index and attributes and the winning
+    // object pixel, if any
+    fn mix_pixel(&self, bg_color: u8, bg_attributes: u8, sprite: Option<(u8, u8)>) -> u32 {
+        let sprite = sprite.filter(|&(color, flags)| {
+            if color == 0 {
+                false
+            } else if self.cgb_mode {
+                // in CGB mode LCDC bit 0 clear puts every object above the background
+                self.lcdc & LCDC_BG_ENABLE == 0
+                    || bg_color == 0
+                    || (bg_attributes & ATTR_PRIORITY == 0 && flags & OBJ_BG_PRIORITY == 0)
+            } else {
+                // BG-over-OBJ only lets background colors 1-3 cover the object
+                flags & OBJ_BG_PRIORITY == 0 || bg_color == 0
+            }
+        });
+
+        match sprite {
+            Some((color, flags)) if self.cgb_mode => {
+                self.rgb(self.obj_palettes.color(flags & ATTR_PALETTE, color))
+            },
+            Some((color, flags)) => {
+                let obp1 = flags & OBJ_PALETTE != 0;
+                let palette = if obp1 { self.obp1 } else { self.obp0 };
+                self.dmg_color(&self.obj_palettes, obp1 as u8, palette_shade(palette, color))
+            },
+            None if self.cgb_mode => {
+                self.rgb(self.bg_palettes.color(bg_attributes & ATTR_PALETTE, bg_color))
+            },
+            None => self.dmg_color(&self.bg_palettes, 0, palette_shade(self.bgp, bg_color)),
+        }
+    }
+
+    // monochrome shade, colorized through palette RAM when CGB hardware runs a DMG game
+    fn dmg_color(&self, palettes: &PaletteRam, palette: u8, shade: usize) -> u32 {
+        if self.model.is_cgb() {
+            self.rgb(palettes.color(palette, shade as u8))
+        } else {
+            self.model.shades()[shade]
+        }
+    }
+
+    fn rgb(&self, rgb555: u16) -> u32 {
+        rgb555_to_rgb888(rgb555, self.color_correction)
+    }
+
+    // color index and attributes of the highest priority opaque object pixel at column x
+    fn sprite_pixel(&self, x: i16) -> Option<(u8, u8)> {
+        self.line_sprites.iter()
+            .filter(|sprite| x >= sprite.x && x < sprite.x + 8)
+            .filter_map(|sprite| {
+                let (low, high) = self.sprite_row(sprite);
+                let color = flipped_row_pixel(low, high, (x - sprite.x) as u8, sprite.flags);
+                (color != 0).then_some((self.sprite_priority(sprite), color, sprite.flags))
+            })
+            .min_by_key(|&(priority, _, _)| priority)
+            .map(|(_, color, flags)| (color, flags))
+    }
+
+    // the two bitplanes of the object row on the current line, after vertical flipping
+    fn sprite_row(&self, sprite: &Sprite) -> (u8, u8) {
+        let height = self.sprite_height();
+        let mut row = self.line as i16 - sprite.y;
+        if sprite.flags & OBJ_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+
+        // in 8x16 mode bit 0 of the tile index is ignored
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        let bank = if self.cgb_mode { sprite.flags } else { 0 };
+        let address = self.tile_data_address(tile as usize * 16, row as u8, bank);
+        (self.vram[address], self.vram[address + 1])
+    }
+
+    // color index and CGB attributes of a pixel in the 256x256 background described by one of
+    // the two tile maps
+    fn tile_pixel(&self, high_map: bool, x: u8, y: u8) -> (u8, u8) {
+        let attributes = self.map_attributes(high_map, x / 8, y);
+        let (low, high) = self.tile_row(high_map, x / 8, y, attributes);
+        (flipped_row_pixel(low, high, x % 8, attributes), attributes)
+    }
+
+    // the two bitplanes of row y of the background tile in map column `column`
+    fn tile_row(&self, high_map: bool, column: u8, y: u8, attributes: u8) -> (u8, u8) {
+        let tile = self.map_tile(high_map, column, y);
+        let address = self.bg_data_address(tile, y, attributes);
+        (self.vram[address], self.vram[address + 1])
+    }
+
+    // address of the low bitplane of row y of a background tile, after vertical flipping
+    fn bg_data_address(&self, tile: u8, y: u8, attributes: u8) -> usize {
+        let row = if attributes & OBJ_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+        self.tile_data_address(self.tile_address(tile), row, attributes)
+    }
+
+    // CGB attributes can place the tile data in VRAM bank 1
+    fn tile_data_address(&self, tile_address: usize, row: u8, attributes: u8) -> usize {
+        let bank = if attributes & ATTR_BANK != 0 { VRAM_BANK_SIZE } else { 0 };
+        bank + tile_address + row as usize * 2
+    }
+
+    fn map_tile(&self, high_map: bool, column: u8, y: u8) -> u8 {
+        self.vram[map_address(high_map, column, y)]
+    }
+
+    // the CGB keeps the attributes of each map entry at the same address in VRAM bank 1
+    fn map_attributes(&self, high_map: bool, column: u8, y: u8) -> u8 {
+        if self.cgb_mode {
+            self.vram[VRAM_BANK_SIZE + map_address(high_map, column, y)]
+        } else {
+            0
+        }
+    }
+
+    // LCDC bit 4 selects unsigned indices from 0x8000 or signed indices around 0x9000
+    fn tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        }
+    }
+
+    // the STAT interrupt only fires when the OR of all enabled sources goes from low to high,
+    // so a source becoming active while another one already holds the line is blocked
+    fn update_stat_line(&mut self) {
+        let line = (self.stat & STAT_LYC_INTERRUPT != 0 && self.ly == self.lyc)
+            || (self.stat & STAT_HBLANK_INTERRUPT != 0 && self.mode == Mode::HBlank)
+            || (self.stat & STAT_VBLANK_INTERRUPT != 0 && self.mode == Mode::VBlank)
+            || (self.stat & STAT_OAM_INTERRUPT != 0 && self.mode == Mode::OamScan);
+
+        if line && !self.stat_line {
+            self.interrupts |= INTERRUPT_STAT;
+        }
+        self.stat_line = line;
+    }
+}
+
+// color index of pixel x (0 = leftmost) of a 2bpp tile row
+fn tile_row_pixel(low: u8, high: u8, x: u8) -> u8 {
+    let bit = 7 - x;
+    (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+}
+
+fn map_address(high_map: bool, column: u8, y: u8) -> usize {
+    let map = if high_map { BG_MAP_HIGH } else { BG_MAP_LOW };
+    map + (y as usize / 8) * 32 + column as usize
+}
+
+// color index of pixel x of a tile row, mirrored when the object or CGB tile is flipped
+fn flipped_row_pixel(low: u8, high: u8, x: u8, flags: u8) -> u8 {
+    let x = if flags & OBJ_X_FLIP != 0 { 7 - x } else { x };
+    tile_row_pixel(low, high, x)
+}
+
+// shade a palette register (BGP, OBP0, OBP1) assigns to a color index
+fn palette_shade(palette: u8, color: u8) -> usize {
+    ((palette >> (color * 2)) & 0x03) as usize
+}
+
+impl FromStr for Renderer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "scanline" => Ok(Renderer::Scanline),
+            "fifo" | "pixel-fifo" => Ok(Renderer::PixelFifo),
+            _ => Err(format!("unknown renderer '{}'", s)),
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_ppu() -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.write_register(0xFF40, LCDC_ENABLE);
+        ppu
+    }
+
+    // run whole dots, collecting every interrupt raised
+    fn run(ppu: &mut Ppu, dots: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..dots {
+            interrupts |= ppu.tick(1);
+        }
+        interrupts
+    }
+
+    #[test]
+    fn test_mode_sequence() {
+        let mut ppu = enabled_ppu();
+        assert_eq!(ppu.get_mode(), Mode::OamScan);
+        run(&mut ppu, 80);
+        assert_eq!(ppu.get_mode(), Mode::Drawing);
+        run(&mut ppu, 172);
+        assert_eq!(ppu.get_mode(), Mode::HBlank);
+        run(&mut ppu, 204);
+        assert_eq!(ppu.get_mode(), Mode::OamScan);
+        assert_eq!(ppu.get_ly(), 1);
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut ppu = enabled_ppu();
+        let interrupts = run(&mut ppu, 456 * 144);
+        assert_eq!(ppu.get_ly(), 144);
+        assert_eq!(ppu.get_mode(), Mode::VBlank);
+        assert_eq!(interrupts & INTERRUPT_VBLANK, INTERRUPT_VBLANK);
+
+        // LY wraps after line 153
+        run(&mut ppu, 456 * 10);
+        assert_eq!(ppu.get_ly(), 0);
+        assert_eq!(ppu.get_mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn test_lyc() {
+        let mut ppu = enabled_ppu();
+        ppu.write_register(0xFF45, 2);
+        ppu.write_register(0xFF41, STAT_LYC_INTERRUPT);
+        ppu.tick(0);    // drop the spurious interrupt from the DMG STAT write bug
+        assert_eq!(run(&mut ppu, 456) & INTERRUPT_STAT, 0);
+        assert_eq!(run(&mut ppu, 456) & INTERRUPT_STAT, INTERRUPT_STAT);
+        assert_eq!(ppu.read_register(0xFF41) & STAT_LYC_EQUAL, STAT_LYC_EQUAL);
+        assert_eq!(ppu.read_register(0xFF41) & 0x03, Mode::OamScan as u8);
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut ppu = enabled_ppu();
+        ppu.write_register(0xFF41, STAT_HBLANK_INTERRUPT | STAT_OAM_INTERRUPT);
+        run(&mut ppu, 80 + 172 - 1);
+
+        // HBlank raises the line, and mode 2 of the next line keeps it high, so no second interrupt
+        assert_eq!(ppu.tick(1) & INTERRUPT_STAT, INTERRUPT_STAT);
+        assert_eq!(run(&mut ppu, 300) & INTERRUPT_STAT, 0);
+    }
+
+    #[test]
+    fn test_stat_write_bug() {
+        let mut dmg = enabled_ppu();
+        run(&mut dmg, 456 * 144);
+        dmg.tick(1);
+        dmg.write_register(0xFF41, 0);
+        assert_eq!(dmg.tick(0) & INTERRUPT_STAT, INTERRUPT_STAT);
+
+        let mut cgb = Ppu::new(Model::Cgb);
+        cgb.write_register(0xFF40, LCDC_ENABLE);
+        run(&mut cgb, 456 * 144);
+        cgb.tick(1);
+        cgb.write_register(0xFF41, 0);
+        assert_eq!(cgb.tick(0) & INTERRUPT_STAT, 0);
+    }
+
+    #[test]
+    fn test_access_blocking() {
+        let mut ppu = enabled_ppu();
+        ppu.write_oam(0xFE00, 0x12);
+        assert_eq!(ppu.read_oam(0xFE00), 0xFF);
+
+        run(&mut ppu, 80);
+        ppu.write_vram(0x8000, 0x34);
+        assert_eq!(ppu.read_vram(0x8000), 0xFF);
+
+        run(&mut ppu, 172);
+        ppu.write_oam(0xFE00, 0x12);
+        ppu.write_vram(0x8000, 0x34);
+        assert_eq!(ppu.read_oam(0xFE00), 0x12);
+        assert_eq!(ppu.read_vram(0x8000), 0x34);
+    }
+
+    // tile 1 is solid color 3, tile 2 has color 1 in its left column only
+    fn ppu_with_tiles(lcdc: u8) -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.write_register(0xFF47, 0xE4);
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0xFF);
+            ppu.write_vram(0x8011 + row * 2, 0xFF);
+            ppu.write_vram(0x8020 + row * 2, 0x80);
+        }
+        ppu.write_register(0xFF40, LCDC_ENABLE | lcdc);
+        ppu
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> u32 {
+        ppu.get_framebuffer()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn test_background() {
+        let mut ppu = ppu_with_tiles(0);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_vram(0x9800, 1);
+        // signed addressing: tile 1 lives at 0x9010, which is still blank
+        ppu.write_vram(0x9801, 1);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE);
+        run(&mut ppu, 456 * 144);
+
+        let shades = Model::Dmg.shades();
+        assert_eq!(ppu.get_frame_count(), 1);
+        assert_eq!(pixel(&ppu, 0, 0), shades[0]);
+
+        let mut ppu = ppu_with_tiles(LCDC_BG_ENABLE | LCDC_TILE_DATA);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_vram(0x9800, 1);
+        ppu.write_vram(0x9801, 2);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_TILE_DATA);
+        run(&mut ppu, 456 * 144);
+
+        assert_eq!(pixel(&ppu, 0, 0), shades[3]);
+        assert_eq!(pixel(&ppu, 7, 7), shades[3]);
+        assert_eq!(pixel(&ppu, 8, 0), shades[1]);
+        assert_eq!(pixel(&ppu, 9, 0), shades[0]);
+        assert_eq!(pixel(&ppu, 0, 8), shades[0]);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let lcdc = LCDC_BG_ENABLE | LCDC_TILE_DATA;
+        let mut ppu = ppu_with_tiles(lcdc);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_vram(0x9800, 2);
+        ppu.write_register(0xFF43, 252);
+        ppu.write_register(0xFF42, 4);
+        ppu.write_register(0xFF40, LCDC_ENABLE | lcdc);
+        run(&mut ppu, 456 * 144);
+
+        let shades = Model::Dmg.shades();
+        // the left column of tile 2 shows up 4 pixels in, and 4 lines are scrolled off the top
+        assert_eq!(pixel(&ppu, 4, 0), shades[1]);
+        assert_eq!(pixel(&ppu, 4, 3), shades[1]);
+        assert_eq!(pixel(&ppu, 4, 4), shades[0]);
+        assert_eq!(pixel(&ppu, 3, 0), shades[0]);
+    }
+
+    #[test]
+    fn test_window() {
+        let lcdc = LCDC_BG_ENABLE | LCDC_TILE_DATA | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP;
+        let mut ppu = ppu_with_tiles(lcdc);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_vram(0x9C00, 1);
+        ppu.write_vram(0x9C20, 2);
+        ppu.write_register(0xFF4A, 10);
+        ppu.write_register(0xFF4B, 7 + 80);
+        ppu.write_register(0xFF40, LCDC_ENABLE | lcdc);
+
+        // hide the window for lines 20-29 by toggling its enable bit; its line counter must pause
+        run(&mut ppu, 456 * 20);
+        ppu.write_register(0xFF40, LCDC_ENABLE | (lcdc & !LCDC_WINDOW_ENABLE));
+        run(&mut ppu, 456 * 10);
+        ppu.write_register(0xFF40, LCDC_ENABLE | lcdc);
+        run(&mut ppu, 456 * 114);
+
+        let shades = Model::Dmg.shades();
+        assert_eq!(pixel(&ppu, 80, 9), shades[0]);
+        assert_eq!(pixel(&ppu, 79, 10), shades[0]);
+        assert_eq!(pixel(&ppu, 80, 10), shades[3]);
+        assert_eq!(pixel(&ppu, 87, 17), shades[3]);
+        assert_eq!(pixel(&ppu, 80, 19), shades[1]);
+        assert_eq!(pixel(&ppu, 80, 25), shades[0]);
+        // window line 10 picks up on screen line 30, still inside the second tile row
+        assert_eq!(pixel(&ppu, 80, 30), shades[1]);
+        assert_eq!(pixel(&ppu, 81, 30), shades[0]);
+        assert_eq!(pixel(&ppu, 80, 35), shades[1]);
+        assert_eq!(pixel(&ppu, 80, 36), shades[0]);
+    }
+
+    fn write_sprite(ppu: &mut Ppu, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        let address = 0xFE00 + index * 4;
+        ppu.write_oam(address, y);
+        ppu.write_oam(address + 1, x);
+        ppu.write_oam(address + 2, tile);
+        ppu.write_oam(address + 3, flags);
+    }
+
+    #[test]
+    fn test_sprites() {
+        let mut ppu = ppu_with_tiles(0);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF48, 0xE4);
+        ppu.write_register(0xFF49, 0x1B);
+        write_sprite(&mut ppu, 0, 16, 8, 1, 0);
+        write_sprite(&mut ppu, 1, 16 + 8, 8 + 20, 1, OBJ_PALETTE);
+        // x flipped, so its single opaque column ends up on the right
+        write_sprite(&mut ppu, 2, 16 + 16, 8 + 40, 2, OBJ_X_FLIP);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE);
+        run(&mut ppu, 456 * 144);
+
+        let shades = Model::Dmg.shades();
+        assert_eq!(pixel(&ppu, 0, 0), shades[3]);
+        assert_eq!(pixel(&ppu, 7, 7), shades[3]);
+        assert_eq!(pixel(&ppu, 8, 0), shades[0]);
+        assert_eq!(pixel(&ppu, 20, 8), shades[0]);
+        assert_eq!(pixel(&ppu, 40, 16), shades[0]);
+        assert_eq!(pixel(&ppu, 47, 16), shades[1]);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = ppu_with_tiles(0);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF48, 0xE4);
+        ppu.write_register(0xFF49, 0x40);
+        // the later entry has the smaller X, so it is drawn on top of the overlap
+        write_sprite(&mut ppu, 0, 16, 8 + 4, 1, 0);
+        write_sprite(&mut ppu, 1, 16, 8, 1, OBJ_PALETTE);
+        // equal X: the lower OAM index wins, the other shows through its transparent pixels
+        write_sprite(&mut ppu, 2, 16 + 8, 8 + 50, 2, 0);
+        write_sprite(&mut ppu, 3, 16 + 8, 8 + 50, 1, 0);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE);
+        run(&mut ppu, 456 * 144);
+
+        let shades = Model::Dmg.shades();
+        assert_eq!(pixel(&ppu, 4, 0), shades[1]);
+        assert_eq!(pixel(&ppu, 7, 0), shades[1]);
+        assert_eq!(pixel(&ppu, 8, 0), shades[3]);
+        assert_eq!(pixel(&ppu, 50, 8), shades[1]);
+        assert_eq!(pixel(&ppu, 51, 8), shades[3]);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut ppu = ppu_with_tiles(0);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF48, 0xE4);
+        for index in 0..12 {
+            write_sprite(&mut ppu, index, 16, 8 + index as u8 * 10, 1, 0);
+        }
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE);
+        run(&mut ppu, 456 * 144);
+
+        let shades = Model::Dmg.shades();
+        assert_eq!(pixel(&ppu, 90, 0), shades[3]);
+        assert_eq!(pixel(&ppu, 100, 0), shades[0]);
+        assert_eq!(pixel(&ppu, 110, 0), shades[0]);
+    }
+
+    #[test]
+    fn test_tall_sprites() {
+        let mut ppu = ppu_with_tiles(0);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF48, 0xE4);
+        // tile index 3 uses tiles 2 (top) and 3 (bottom, blank); flipped vertically they swap
+        write_sprite(&mut ppu, 0, 16, 8, 3, 0);
+        write_sprite(&mut ppu, 1, 16, 8 + 20, 3, OBJ_Y_FLIP);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE | LCDC_OBJ_SIZE);
+        run(&mut ppu, 456 * 144);
+
+        let shades = Model::Dmg.shades();
+        assert_eq!(pixel(&ppu, 0, 0), shades[1]);
+        assert_eq!(pixel(&ppu, 0, 8), shades[0]);
+        assert_eq!(pixel(&ppu, 20, 0), shades[0]);
+        assert_eq!(pixel(&ppu, 20, 15), shades[1]);
+    }
+
+    #[test]
+    fn test_sprite_bg_priority() {
+        let lcdc = LCDC_BG_ENABLE | LCDC_TILE_DATA;
+        let mut ppu = ppu_with_tiles(lcdc);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF48, 0xE4);
+        ppu.write_vram(0x9800, 2);
+        write_sprite(&mut ppu, 0, 16, 8, 1, OBJ_BG_PRIORITY);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE | lcdc);
+        run(&mut ppu, 456 * 144);
+
+        // only background color 0 lets the object through
+        let shades = Model::Dmg.shades();
+        assert_eq!(pixel(&ppu, 0, 0), shades[1]);
+        assert_eq!(pixel(&ppu, 1, 0), shades[3]);
+    }
+
+    fn cgb_ppu(renderer: Renderer) -> Ppu {
+        let mut ppu = Ppu::new(Model::Cgb);
+        ppu.set_cgb_mode(true);
+        ppu.set_renderer(renderer);
+        ppu
+    }
+
+    fn write_palette(ppu: &mut Ppu, register: u16, palette: u8, colors: [u16; 4]) {
+        ppu.write_register(register, 0x80 | (palette * 8));
+        for color in colors {
+            ppu.write_register(register + 1, (color & 0xFF) as u8);
+            ppu.write_register(register + 1, (color >> 8) as u8);
+        }
+    }
+
+    // tile 1 in bank 0 is solid color 1, tile 1 in bank 1 has color 3 in its top-left pixel only
+    fn cgb_scene(renderer: Renderer) -> Ppu {
+        let mut ppu = cgb_ppu(renderer);
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0xFF);
+        }
+        ppu.write_register(0xFF4F, 1);
+        ppu.write_vram(0x8010, 0x80);
+        ppu.write_vram(0x8011, 0x80);
+        ppu.write_register(0xFF4F, 0);
+
+        write_palette(&mut ppu, 0xFF68, 0, [0x0000, 0x001F, 0x03E0, 0x7C00]);
+        write_palette(&mut ppu, 0xFF68, 5, [0x0000, 0x7FFF, 0x0000, 0x03FF]);
+        write_palette(&mut ppu, 0xFF6A, 0, [0x0000, 0x7FFF, 0x0000, 0x0000]);
+        write_palette(&mut ppu, 0xFF6A, 2, [0x0000, 0x7C1F, 0x0000, 0x0000]);
+        ppu
+    }
+
+    #[test]
+    fn test_vram_banks() {
+        let mut ppu = cgb_ppu(Renderer::Scanline);
+        ppu.write_vram(0x8000, 0x11);
+        ppu.write_register(0xFF4F, 0xFF);
+        assert_eq!(ppu.read_register(0xFF4F), 0xFF);
+        assert_eq!(ppu.read_vram(0x8000), 0x00);
+        ppu.write_vram(0x8000, 0x22);
+        ppu.write_register(0xFF4F, 0);
+        assert_eq!(ppu.read_register(0xFF4F), 0xFE);
+        assert_eq!(ppu.read_vram(0x8000), 0x11);
+
+        // no banking outside CGB mode
+        let mut dmg = Ppu::new(Model::Dmg);
+        dmg.write_register(0xFF4F, 1);
+        assert_eq!(dmg.read_register(0xFF4F), 0xFF);
+        assert_eq!(dmg.read_register(0xFF68), 0xFF);
+    }
+
+    #[test]
+    fn test_palette_access() {
+        let mut ppu = cgb_ppu(Renderer::Scanline);
+        write_palette(&mut ppu, 0xFF68, 1, [0x1234, 0, 0, 0]);
+        ppu.write_register(0xFF68, 0x08);
+        assert_eq!(ppu.read_register(0xFF69), 0x34);
+        assert_eq!(ppu.read_register(0xFF68), 0x48);
+
+        // inaccessible while the PPU reads palettes in mode 3
+        ppu.write_register(0xFF40, LCDC_ENABLE);
+        run(&mut ppu, 80);
+        assert_eq!(ppu.read_register(0xFF69), 0xFF);
+        ppu.write_register(0xFF68, 0x88);
+        ppu.write_register(0xFF69, 0x00);
+        assert_eq!(ppu.read_register(0xFF68), 0xC9);
+        run(&mut ppu, 172);
+        ppu.write_register(0xFF68, 0x08);
+        assert_eq!(ppu.read_register(0xFF69), 0x34);
+    }
+
+    #[test]
+    fn test_cgb_attributes() {
+        for renderer in [Renderer::Scanline, Renderer::PixelFifo] {
+            let mut ppu = cgb_scene(renderer);
+            ppu.write_vram(0x9800, 1);
+            ppu.write_vram(0x9801, 1);
+            ppu.write_vram(0x9802, 1);
+            ppu.write_register(0xFF4F, 1);
+            ppu.write_vram(0x9801, ATTR_BANK | 5);
+            ppu.write_vram(0x9802, ATTR_BANK | OBJ_X_FLIP | OBJ_Y_FLIP);
+            ppu.write_register(0xFF4F, 0);
+            ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_TILE_DATA);
+            run(&mut ppu, 456 * 144);
+
+            assert_eq!(pixel(&ppu, 0, 0), 0x00FF0000, "{:?}", renderer);
+            assert_eq!(pixel(&ppu, 8, 0), 0x00FFFF00);
+            assert_eq!(pixel(&ppu, 9, 0), 0x00000000);
+            // flipped both ways the lone pixel moves to the bottom-right corner
+            assert_eq!(pixel(&ppu, 16, 0), 0x00000000);
+            assert_eq!(pixel(&ppu, 23, 7), 0x000000FF);
+        }
+    }
+
+    #[test]
+    fn test_cgb_priority() {
+        for renderer in [Renderer::Scanline, Renderer::PixelFifo] {
+            let mut ppu = cgb_scene(renderer);
+            ppu.write_vram(0x9800, 1);
+            ppu.write_vram(0x9802, 1);
+            ppu.write_register(0xFF4F, 1);
+            ppu.write_vram(0x9802, ATTR_PRIORITY);
+            ppu.write_register(0xFF4F, 0);
+            // in CGB mode the lower OAM index wins even though it is further right
+            write_sprite(&mut ppu, 0, 16, 8 + 4, 1, 2);
+            write_sprite(&mut ppu, 1, 16, 8, 1, 0);
+            // the tile's priority bit keeps this object behind the background
+            write_sprite(&mut ppu, 2, 16, 8 + 16, 1, 2);
+            ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE | LCDC_TILE_DATA);
+            run(&mut ppu, 456 * 144);
+
+            let magenta = rgb555_to_rgb888(0x7C1F, false);
+            assert_eq!(pixel(&ppu, 0, 0), rgb555_to_rgb888(0x7FFF, false), "{:?}", renderer);
+            assert_eq!(pixel(&ppu, 4, 0), magenta);
+            assert_eq!(pixel(&ppu, 16, 0), 0x00FF0000);
+
+            // OPRI bit 0 switches back to the DMG order, the leftmost object wins
+            let mut ppu = cgb_scene(renderer);
+            ppu.write_register(0xFF6C, 0x01);
+            assert_eq!(ppu.read_register(0xFF6C), 0xFF);
+            write_sprite(&mut ppu, 0, 16, 8 + 4, 1, 2);
+            write_sprite(&mut ppu, 1, 16, 8, 1, 0);
+            ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE | LCDC_TILE_DATA);
+            run(&mut ppu, 456 * 144);
+            assert_eq!(pixel(&ppu, 4, 0), rgb555_to_rgb888(0x7FFF, false));
+
+            // with LCDC bit 0 clear every object is drawn above the background
+            let mut ppu = cgb_scene(renderer);
+            ppu.write_vram(0x9801, 1);
+            ppu.write_vram(0x9802, 1);
+            ppu.write_register(0xFF4F, 1);
+            ppu.write_vram(0x9802, ATTR_PRIORITY);
+            ppu.write_register(0xFF4F, 0);
+            write_sprite(&mut ppu, 2, 16, 8 + 16, 1, 2);
+            ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_OBJ_ENABLE | LCDC_TILE_DATA);
+            run(&mut ppu, 456 * 144);
+            assert_eq!(pixel(&ppu, 16, 0), magenta);
+            // but unlike DMG the background is still drawn
+            assert_eq!(pixel(&ppu, 8, 0), 0x00FF0000);
+        }
+    }
+
+    #[test]
+    fn test_compat_colors() {
+        let mut ppu = Ppu::new(Model::Cgb);
+        ppu.boot();
+        ppu.write_register(0xFF40, 0);
+        ppu.write_register(0xFF47, 0xE4);
+        ppu.set_color_correction(true);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE);
+        run(&mut ppu, 456 * 144);
+
+        assert_eq!(pixel(&ppu, 0, 0), rgb555_to_rgb888(COMPAT_BG_PALETTE[0], true));
+        assert_eq!(ppu.read_register(0xFF69), 0xFF);
+    }
+
+    #[test]
+    fn test_lcd_off() {
+        let mut ppu = enabled_ppu();
+        run(&mut ppu, 456 * 3 + 100);
+        ppu.write_register(0xFF40, 0);
+        assert_eq!(ppu.get_ly(), 0);
+        assert_eq!(ppu.read_register(0xFF41) & 0x03, 0);
+
+        // HBlank and LY == LYC both hold while it's off, but the STAT line stays low
+        ppu.write_register(0xFF41, STAT_HBLANK_INTERRUPT | STAT_LYC_INTERRUPT);
+        assert_eq!(run(&mut ppu, 1000) & INTERRUPT_STAT, 0);
+        assert_eq!(ppu.get_ly(), 0);
+    }
+}
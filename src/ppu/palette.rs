@@ -0,0 +1,125 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const PALETTE_RAM_SIZE: usize = 64;     // 8 palettes of 4 RGB555 colors
+const AUTO_INCREMENT: u8 = 0x80;
+
+/* ----- TYPE DECLARATIONS ----- */
+// CGB palette memory behind BCPS/BCPD or OCPS/OCPD
+pub(super) struct PaletteRam {
+    data: [u8; PALETTE_RAM_SIZE],
+    spec: u8,   // bits 0-5 address, bit 7 auto-increment after data writes
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl PaletteRam {
+    pub(super) fn new() -> PaletteRam {
+        PaletteRam {
+            data: [0; PALETTE_RAM_SIZE],
+            spec: 0,
+        }
+    }
+
+    pub(super) fn read_spec(&self) -> u8 {
+        self.spec | 0x40
+    }
+
+    pub(super) fn write_spec(&mut self, value: u8) {
+        self.spec = value & 0xBF;
+    }
+
+    pub(super) fn read_data(&self) -> u8 {
+        self.data[self.address()]
+    }
+
+    pub(super) fn write_data(&mut self, value: u8) {
+        self.data[self.address()] = value;
+        if self.spec & AUTO_INCREMENT != 0 {
+            self.spec = AUTO_INCREMENT | ((self.spec + 1) & 0x3F);
+        }
+    }
+
+    // writes from inside the PPU (boot palettes) that leave the spec register alone
+    pub(super) fn set_color(&mut self, palette: u8, color: u8, rgb555: u16) {
+        let index = palette as usize * 8 + color as usize * 2;
+        self.data[index..index + 2].copy_from_slice(&rgb555.to_le_bytes());
+    }
+
+    pub(super) fn color(&self, palette: u8, color: u8) -> u16 {
+        let index = palette as usize * 8 + color as usize * 2;
+        u16::from_le_bytes([self.data[index], self.data[index + 1]])
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.spec);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.data)?;
+        self.spec = state.read_u8()? & 0xBF;
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    fn address(&self) -> usize {
+        (self.spec & 0x3F) as usize
+    }
+}
+
+// expand a 15-bit CGB color to 0x00RRGGBB, optionally through the LCD's color response curve
+pub(super) fn rgb555_to_rgb888(rgb555: u16, correct: bool) -> u32 {
+    let r = (rgb555 & 0x1F) as u32;
+    let g = ((rgb555 >> 5) & 0x1F) as u32;
+    let b = ((rgb555 >> 10) & 0x1F) as u32;
+
+    let (r, g, b) = if correct {
+        // the CGB screen mixes neighbouring channels and never reaches full brightness
+        (
+            (r * 26 + g * 4 + b * 2).min(960) >> 2,
+            (g * 24 + b * 8).min(960) >> 2,
+            (r * 6 + g * 4 + b * 22).min(960) >> 2,
+        )
+    } else {
+        ((r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2))
+    };
+
+    (r << 16) | (g << 8) | b
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_increment() {
+        let mut ram = PaletteRam::new();
+        ram.write_spec(AUTO_INCREMENT | 0x3E);
+        ram.write_data(0x1F);
+        ram.write_data(0x7C);
+        ram.write_data(0xE0);
+
+        // the address wraps around after the last byte
+        assert_eq!(ram.read_spec(), 0xC1);
+        assert_eq!(ram.color(7, 3), 0x7C1F);
+        assert_eq!(ram.color(0, 0), 0x00E0);
+
+        // reads never increment
+        ram.write_spec(0x3E);
+        assert_eq!(ram.read_data(), 0x1F);
+        assert_eq!(ram.read_data(), 0x1F);
+    }
+
+    #[test]
+    fn test_rgb555() {
+        assert_eq!(rgb555_to_rgb888(0x7FFF, false), 0x00FFFFFF);
+        assert_eq!(rgb555_to_rgb888(0x001F, false), 0x00FF0000);
+        assert_eq!(rgb555_to_rgb888(0x03E0, false), 0x0000FF00);
+        assert_eq!(rgb555_to_rgb888(0x7C00, false), 0x000000FF);
+        assert_eq!(rgb555_to_rgb888(0x0000, true), 0x00000000);
+        assert_eq!(rgb555_to_rgb888(0x7FFF, true), 0x00F0F0F0);
+        // pure red bleeds into blue through the correction curve
+        assert_ne!(rgb555_to_rgb888(0x001F, true) & 0xFF, 0);
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::VecDeque;
+use crate::state::{StateError, StateReader, StateWriter};
+use super::*;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const DUMMY_FETCH_DOTS: u8 = 6;     // the first tile fetch of every line is thrown away
+const SPRITE_FETCH_DOTS: u8 = 6;
+const SPRITE_WAIT_DOTS: u8 = 5;     // worst case wait for the background fetcher to finish a tile
+const STEP_DOTS: u8 = 2;            // every fetcher step but the push takes two dots
+
+/* ----- TYPE DECLARATIONS ----- */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct BgPixel {
+    color: u8,
+    attributes: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    flags: u8,
+    priority: u16,  // see Ppu::sprite_priority
+}
+
+// state of the background/window fetcher and the two pixel FIFOs for the line being drawn
+pub(super) struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    step: FetchStep,
+    step_dots: u8,
+    map_x: u8,          // tile column counter, relative to SCX or to the window's left edge
+    tile: u8,
+    attributes: u8,     // CGB attributes of the tile being fetched
+    row: u8,            // line within the background or window the tile is fetched for
+    low: u8,
+    high: u8,
+    fetching_window: bool,
+    x: u8,              // pixels shifted out to the LCD so far
+    discard: u8,        // SCX fine scroll pixels still to be dropped
+    stall: u8,          // dots where the fetcher and the FIFOs are both idle
+    next_sprite: usize, // first entry of the line's sprite list not fetched yet
+    fine_scroll: u8,    // SCX & 7 when the line started
+    waited_tile: Option<(bool, u8)>,  // last background/window tile an object fetch waited on
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl PixelFifo {
+    pub(super) fn new() -> PixelFifo {
+        PixelFifo {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            map_x: 0,
+            tile: 0,
+            attributes: 0,
+            row: 0,
+            low: 0,
+            high: 0,
+            fetching_window: false,
+            x: 0,
+            discard: 0,
+            stall: 0,
+            next_sprite: 0,
+            fine_scroll: 0,
+            waited_tile: None,
+        }
+    }
+
+    pub(super) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.bg.len() as u8);
+        for pixel in &self.bg {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.attributes);
+        }
+        state.write_u8(self.obj.len() as u8);
+        for pixel in &self.obj {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.flags);
+            state.write_u16(pixel.priority);
+        }
+        state.write_u8(self.step as u8);
+        state.write_u8(self.step_dots);
+        state.write_u8(self.map_x);
+        state.write_u8(self.tile);
+        state.write_u8(self.attributes);
+        state.write_u8(self.row);
+        state.write_u8(self.low);
+        state.write_u8(self.high);
+        state.write_bool(self.fetching_window);
+        state.write_u8(self.x);
+        state.write_u8(self.discard);
+        state.write_u8(self.stall);
+        state.write_u8(self.next_sprite as u8);
+        state.write_u8(self.fine_scroll);
+        let (window, tile) = self.waited_tile.unwrap_or_default();
+        state.write_bool(self.waited_tile.is_some());
+        state.write_bool(window);
+        state.write_u8(tile);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut fifo = PixelFifo::new();
+        for _ in 0..state.read_u8()? {
+            let color = state.read_u8()?;
+            fifo.bg.push_back(BgPixel { color, attributes: state.read_u8()? });
+        }
+        for _ in 0..state.read_u8()? {
+            let color = state.read_u8()?;
+            let flags = state.read_u8()?;
+            fifo.obj.push_back(ObjPixel { color, flags, priority: state.read_u16()? });
+        }
+        fifo.step = match state.read_u8()? {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return Err(StateError::Invalid("pixel fetcher step")),
+        };
+        fifo.step_dots = state.read_u8()?;
+        fifo.map_x = state.read_u8()?;
+        fifo.tile = state.read_u8()?;
+        fifo.attributes = state.read_u8()?;
+        fifo.row = state.read_u8()?;
+        fifo.low = state.read_u8()?;
+        fifo.high = state.read_u8()?;
+        fifo.fetching_window = state.read_bool()?;
+        fifo.x = state.read_u8()?;
+        fifo.discard = state.read_u8()?;
+        fifo.stall = state.read_u8()?;
+        fifo.next_sprite = state.read_u8()? as usize;
+        fifo.fine_scroll = state.read_u8()?;
+        let waited = state.read_bool()?;
+        let window = state.read_bool()?;
+        let tile = state.read_u8()?;
+        fifo.waited_tile = if waited { Some((window, tile)) } else { None };
+        *self = fifo;
+        Ok(())
+    }
+}
+
+impl Ppu {
+    pub(super) fn fifo_start_line(&mut self) {
+        self.fifo = PixelFifo::new();
+        self.fifo.fine_scroll = self.scx & 0x07;
+        self.fifo.discard = self.fifo.fine_scroll;
+        self.fifo.stall = DUMMY_FETCH_DOTS;
+    }
+
+    // run one dot of mode 3, returns true once all 160 pixels of the line have been output
+    pub(super) fn fifo_dot(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        // the window restarts the fetcher and throws away what was queued
+        if !self.fifo.fetching_window
+            && self.fifo.discard == 0
+            && (self.cgb_mode || self.lcdc & LCDC_BG_ENABLE != 0)
+            && self.window_visible()
+            && self.fifo.x as u16 + 7 >= self.wx as u16
+        {
+            self.fifo.fetching_window = true;
+            self.fifo.bg.clear();
+            self.fifo.step = FetchStep::Tile;
+            self.fifo.step_dots = 0;
+            self.fifo.map_x = 0;
+        }
+
+        self.fetcher_dot();
+
+        // an object at the current position halts the output until it has been fetched
+        if self.sprite_pending() && !self.fifo.bg.is_empty() {
+            self.fetch_sprite();
+            return false;
+        }
+
+        self.shift_pixel()
+    }
+
+    /* ----- PRIVATE ----- */
+    fn sprite_pending(&self) -> bool {
+        self.lcdc & LCDC_OBJ_ENABLE != 0
+            && self.fifo.discard == 0
+            && self.line_sprites.get(self.fifo.next_sprite)
+                .is_some_and(|sprite| sprite.x <= self.fifo.x as i16)
+    }
+
+    fn fetcher_dot(&mut self) {
+        if self.fifo.step != FetchStep::Push {
+            self.fifo.step_dots += 1;
+            if self.fifo.step_dots < STEP_DOTS {
+                return;
+            }
+            self.fifo.step_dots = 0;
+        }
+
+        match self.fifo.step {
+            FetchStep::Tile => {
+                // SCX and SCY are sampled per tile, so mid-line writes take effect at the next fetch
+                let (map, column, row) = if self.fifo.fetching_window {
+                    (self.lcdc & LCDC_WINDOW_MAP != 0, self.fifo.map_x, self.window_line)
+                } else {
+                    let column = ((self.scx >> 3).wrapping_add(self.fifo.map_x)) & 0x1F;
+                    (self.lcdc & LCDC_BG_MAP != 0, column, self.line.wrapping_add(self.scy))
+                };
+                self.fifo.tile = self.map_tile(map, column, row);
+                self.fifo.attributes = self.map_attributes(map, column, row);
+                self.fifo.row = row;
+                self.fifo.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                self.fifo.low = self.vram[self.fifo_data_address()];
+                self.fifo.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                self.fifo.high = self.vram[self.fifo_data_address() + 1];
+                self.fifo.step = FetchStep::Push;
+            },
+            FetchStep::Push => {
+                if self.fifo.bg.is_empty() {
+                    let attributes = self.fifo.attributes;
+                    for x in 0..8 {
+                        let color = flipped_row_pixel(self.fifo.low, self.fifo.high, x, attributes);
+                        self.fifo.bg.push_back(BgPixel { color, attributes });
+                    }
+                    self.fifo.map_x = self.fifo.map_x.wrapping_add(1);
+                    self.fifo.step = FetchStep::Tile;
+                }
+            },
+        }
+    }
+
+    fn fifo_data_address(&self) -> usize {
+        self.bg_data_address(self.fifo.tile, self.fifo.row, self.fifo.attributes)
+    }
+
+    // merge the object's row into the object FIFO, an opaque pixel only replaces a transparent
+    // one or one of an object with lower priority
+    fn fetch_sprite(&mut self) {
+        let sprite = self.line_sprites[self.fifo.next_sprite];
+        self.fifo.next_sprite += 1;
+
+        // the first object over a tile also waits for the background fetcher to finish that tile,
+        // which takes longer the closer the object is to the tile's left edge
+        let position = if self.fifo.fetching_window {
+            (self.fifo.x + 7).wrapping_sub(self.wx)
+        } else {
+            self.fifo.x.wrapping_add(self.fifo.fine_scroll)
+        };
+        let tile = (self.fifo.fetching_window, position / 8);
+        let wait = if self.fifo.waited_tile == Some(tile) {
+            0
+        } else {
+            SPRITE_WAIT_DOTS.saturating_sub(position % 8)
+        };
+        self.fifo.waited_tile = Some(tile);
+        // the current dot is the first one of the fetch
+        self.fifo.stall = SPRITE_FETCH_DOTS + wait - 1;
+
+        let (low, high) = self.sprite_row(&sprite);
+        let priority = self.sprite_priority(&sprite);
+        let skipped = (self.fifo.x as i16 - sprite.x) as u8;
+        for column in skipped..8 {
+            let pixel = ObjPixel {
+                color: flipped_row_pixel(low, high, column, sprite.flags),
+                flags: sprite.flags,
+                priority,
+            };
+            let slot = (column - skipped) as usize;
+            if slot >= self.fifo.obj.len() {
+                self.fifo.obj.push_back(pixel);
+            } else {
+                let queued = self.fifo.obj[slot];
+                if queued.color == 0 || (pixel.color != 0 && pixel.priority < queued.priority) {
+                    self.fifo.obj[slot] = pixel;
+                }
+            }
+        }
+    }
+
+    fn shift_pixel(&mut self) -> bool {
+        let Some(pixel) = self.fifo.bg.pop_front() else {
+            return false;
+        };
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+
+        let sprite = self.fifo.obj.pop_front()
+            .filter(|_| self.lcdc & LCDC_OBJ_ENABLE != 0)
+            .map(|pixel| (pixel.color, pixel.flags));
+        let (bg_color, attributes) = if self.cgb_mode || self.lcdc & LCDC_BG_ENABLE != 0 {
+            (pixel.color, pixel.attributes)
+        } else {
+            (0, 0)
+        };
+        let index = self.line as usize * SCREEN_WIDTH + self.fifo.x as usize;
+        self.framebuffer[index] = self.mix_pixel(bg_color, attributes, sprite);
+        self.fifo.x += 1;
+
+        if self.fifo.x as usize == SCREEN_WIDTH {
+            if self.fifo.fetching_window {
+                self.window_line += 1;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a tile per map entry, each row of tile n holds the bit pattern n so every column differs
+    fn scene(renderer: Renderer) -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.set_renderer(renderer);
+        ppu.write_register(0xFF47, 0xE4);
+        ppu.write_register(0xFF48, 0xD2);
+        ppu.write_register(0xFF49, 0x39);
+        for tile in 0..256u16 {
+            for row in 0..8 {
+                ppu.write_vram(0x8000 + tile * 16 + row * 2, tile as u8);
+                ppu.write_vram(0x8001 + tile * 16 + row * 2, (tile as u8).rotate_left(row as u32));
+            }
+        }
+        for entry in 0..0x800u16 {
+            ppu.write_vram(0x9800 + entry, (entry * 7) as u8);
+        }
+        for index in 0..40u16 {
+            ppu.write_oam(0xFE00 + index * 4, (index * 13 % 150) as u8 + 8);
+            ppu.write_oam(0xFE01 + index * 4, (index * 29 % 168) as u8);
+            ppu.write_oam(0xFE02 + index * 4, (index * 5) as u8);
+            ppu.write_oam(0xFE03 + index * 4, ((index % 8) << 4) as u8);
+        }
+        ppu.write_register(0xFF42, 3);
+        ppu.write_register(0xFF43, 13);
+        ppu.write_register(0xFF4A, 40);
+        ppu.write_register(0xFF4B, 60);
+        ppu
+    }
+
+    fn run_frame(ppu: &mut Ppu) {
+        let frame = ppu.get_frame_count();
+        while ppu.get_frame_count() == frame {
+            ppu.tick(4);
+        }
+    }
+
+    // dots spent in mode 3 on the next line to be drawn
+    fn drawing_dots(ppu: &mut Ppu) -> u32 {
+        while ppu.get_mode() != Mode::Drawing {
+            ppu.tick(1);
+        }
+        let mut dots = 0;
+        while ppu.get_mode() == Mode::Drawing {
+            ppu.tick(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_matches_scanline() {
+        for lcdc in [0xF3, 0xE3, 0x97, 0xD1, 0xB3] {
+            let mut scanline = scene(Renderer::Scanline);
+            let mut fifo = scene(Renderer::PixelFifo);
+            scanline.write_register(0xFF40, lcdc);
+            fifo.write_register(0xFF40, lcdc);
+            run_frame(&mut scanline);
+            run_frame(&mut fifo);
+
+            assert!(scanline.get_framebuffer() == fifo.get_framebuffer(), "LCDC {:02X}", lcdc);
+        }
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.set_renderer(Renderer::PixelFifo);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE);
+        assert_eq!(drawing_dots(&mut ppu), 172);
+
+        // fine scroll drops SCX & 7 pixels at the start of the line
+        ppu.write_register(0xFF43, 0x0B);
+        assert_eq!(drawing_dots(&mut ppu), 175);
+        ppu.write_register(0xFF43, 0);
+
+        // the window restarts the fetcher
+        ppu.write_register(0xFF4B, 7 + 40);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_WINDOW_ENABLE);
+        assert_eq!(drawing_dots(&mut ppu), 178);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE);
+
+        // an object at X=0 costs 11 dots, one aligned with the fetcher only 6
+        ppu.write_register(0xFF40, 0);
+        ppu.write_oam(0xFE00, 16);
+        ppu.write_oam(0xFE01, 8);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE);
+        assert_eq!(drawing_dots(&mut ppu), 172 + 11);
+        ppu.write_register(0xFF40, 0);
+        ppu.write_oam(0xFE01, 8 + 13);
+        ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE | LCDC_OBJ_ENABLE);
+        assert_eq!(drawing_dots(&mut ppu), 172 + 6);
+    }
+
+    #[test]
+    fn test_mid_scanline_writes() {
+        for renderer in [Renderer::Scanline, Renderer::PixelFifo] {
+            let mut ppu = Ppu::new(Model::Dmg);
+            ppu.set_renderer(renderer);
+            ppu.write_register(0xFF47, 0x00);
+            ppu.write_register(0xFF40, LCDC_ENABLE | LCDC_BG_ENABLE);
+
+            // switch BGP to all-black halfway through mode 3 of line 0
+            ppu.tick(80 + 12 + 80);
+            ppu.write_register(0xFF47, 0xFF);
+            run_frame(&mut ppu);
+
+            let shades = Model::Dmg.shades();
+            let line = &ppu.get_framebuffer()[..SCREEN_WIDTH];
+            match renderer {
+                Renderer::Scanline => assert!(line.iter().all(|&pixel| pixel == shades[3])),
+                Renderer::PixelFifo => {
+                    assert!(line[..80].iter().all(|&pixel| pixel == shades[0]));
+                    assert!(line[80..].iter().all(|&pixel| pixel == shades[3]));
+                },
+            }
+        }
+    }
+}
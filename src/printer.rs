@@ -0,0 +1,477 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::serial::SerialDevice;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+const TILE_ROW_BYTES: usize = TILES_PER_ROW * 16;   // 2bpp data for one 8 pixel high row of tiles
+const MAX_DATA: usize = TILE_ROW_BYTES * 18;        // 9 bands of 16 pixels fill the buffer
+const BUSY_INQUIRIES: u8 = 2;                       // status inquiries answered with "printing"
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+/* ----- TYPE DECLARATIONS ----- */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PrintFormat {
+    Png,
+    Pgm,
+}
+
+// where the printer is in a packet: magic, command, compression, length, data, checksum,
+// then the two bytes it answers with its ID and status
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum PacketStep {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    DeviceId,
+    Status,
+}
+
+// Game Boy Printer, every finished page is written to the output directory
+pub struct Printer {
+    directory: PathBuf,
+    format: PrintFormat,
+    step: PacketStep,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    sum: u16,               // running checksum over command, compression, length and data
+    checksum: u16,          // checksum sent by the Game Boy
+    status: u8,
+    busy: u8,
+    buffer: Vec<u8>,        // 2bpp tile data received since the last print
+    page: Vec<u8>,          // shades of the page printed so far, WIDTH per line
+    unsaved: Vec<Vec<u8>>,  // finished pages that failed to save, oldest first
+    last_error: Option<io::Error>,
+    printed: Vec<PathBuf>,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PrintFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(PrintFormat::Png),
+            "pgm" => Ok(PrintFormat::Pgm),
+            _ => Err(format!("unknown print format '{}', expected png or pgm", s)),
+        }
+    }
+}
+
+impl PrintFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            PrintFormat::Png => "png",
+            PrintFormat::Pgm => "pgm",
+        }
+    }
+}
+
+impl Printer {
+    pub fn new<P: Into<PathBuf>>(directory: P, format: PrintFormat) -> Printer {
+        Printer {
+            directory: directory.into(),
+            format,
+            step: PacketStep::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            sum: 0,
+            checksum: 0,
+            status: 0,
+            busy: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            unsaved: Vec::new(),
+            last_error: None,
+            printed: Vec::new(),
+        }
+    }
+
+    // files written so far
+    pub fn get_printed(&self) -> &[PathBuf] {
+        &self.printed
+    }
+
+    // why the last page failed to save, cleared when read
+    pub fn take_last_error(&mut self) -> Option<io::Error> {
+        self.last_error.take()
+    }
+
+    pub fn get_last_error(&self) -> Option<&io::Error> {
+        self.last_error.as_ref()
+    }
+
+    // finished pages still waiting to be saved
+    pub fn get_unsaved_pages(&self) -> usize {
+        self.unsaved.len()
+    }
+
+    // try the pages that failed to save again, in order, stopping at the first that still fails
+    pub fn save_unsaved(&mut self) -> io::Result<()> {
+        while let Some(page) = self.unsaved.first() {
+            let path = self.save_page(page)?;
+            self.printed.push(path);
+            self.unsaved.remove(0);
+        }
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    fn run_command(&mut self) {
+        if self.sum != self.checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy = 0;
+            },
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&self.packet) } else { self.packet.clone() };
+                let room = MAX_DATA - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+            },
+            COMMAND_PRINT if self.packet.len() >= 4 => {
+                self.print(self.packet[1], self.packet[2]);
+                self.status = (self.status & !STATUS_UNPROCESSED) | STATUS_PRINTING;
+                self.busy = BUSY_INQUIRIES;
+            },
+            COMMAND_STATUS if self.busy > 0 => {
+                self.busy -= 1;
+                if self.busy == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // decode the buffer through the palette onto the page, a feed after the print ends the page
+    fn print(&mut self, margins: u8, palette: u8) {
+        for tile_row in self.buffer.chunks_exact(TILE_ROW_BYTES) {
+            for line in 0..8 {
+                for x in 0..WIDTH {
+                    let tile = &tile_row[(x / 8) * 16..];
+                    let bit = 7 - (x % 8);
+                    let low = (tile[line * 2] >> bit) & 0x01;
+                    let high = (tile[line * 2 + 1] >> bit) & 0x01;
+                    let color = (high << 1) | low;
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    self.page.push(SHADES[shade as usize]);
+                }
+            }
+        }
+        self.buffer.clear();
+
+        if margins & 0x0F != 0 && !self.page.is_empty() {
+            self.unsaved.push(std::mem::take(&mut self.page));
+            if let Err(e) = self.save_unsaved() {
+                self.last_error = Some(e);
+            }
+        }
+    }
+
+    fn save_page(&self, page: &[u8]) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.directory)?;
+        let mut number = self.printed.len() + 1;
+        let path = loop {
+            let path = self.directory.join(format!("print_{:04}.{}", number, self.format.extension()));
+            if !path.exists() {
+                break path;
+            }
+            number += 1;
+        };
+
+        let height = page.len() / WIDTH;
+        let data = match self.format {
+            PrintFormat::Png => encode_png(WIDTH, height, page),
+            PrintFormat::Pgm => encode_pgm(WIDTH, height, page),
+        };
+        fs::write(&path, data)?;
+        Ok(path)
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut answer = 0x00;
+        self.step = match self.step {
+            PacketStep::Magic(index) if outgoing == MAGIC[index] => {
+                if index == 0 { PacketStep::Magic(1) } else { PacketStep::Command }
+            },
+            PacketStep::Magic(_) => PacketStep::Magic(usize::from(outgoing == MAGIC[0])),
+            PacketStep::Command => {
+                self.command = outgoing;
+                self.sum = outgoing as u16;
+                self.packet.clear();
+                PacketStep::Compression
+            },
+            PacketStep::Compression => {
+                self.compressed = outgoing & 0x01 != 0;
+                self.sum = self.sum.wrapping_add(outgoing as u16);
+                PacketStep::Length(0)
+            },
+            PacketStep::Length(0) => {
+                self.length = outgoing as u16;
+                self.sum = self.sum.wrapping_add(outgoing as u16);
+                PacketStep::Length(1)
+            },
+            PacketStep::Length(_) => {
+                self.length |= (outgoing as u16) << 8;
+                self.sum = self.sum.wrapping_add(outgoing as u16);
+                if self.length == 0 { PacketStep::Checksum(0) } else { PacketStep::Data }
+            },
+            PacketStep::Data => {
+                self.packet.push(outgoing);
+                self.sum = self.sum.wrapping_add(outgoing as u16);
+                if self.packet.len() == self.length as usize { PacketStep::Checksum(0) } else { PacketStep::Data }
+            },
+            PacketStep::Checksum(0) => {
+                self.checksum = outgoing as u16;
+                PacketStep::Checksum(1)
+            },
+            PacketStep::Checksum(_) => {
+                self.checksum |= (outgoing as u16) << 8;
+                PacketStep::DeviceId
+            },
+            PacketStep::DeviceId => {
+                answer = DEVICE_ID;
+                self.run_command();
+                PacketStep::Status
+            },
+            PacketStep::Status => {
+                answer = self.status;
+                PacketStep::Magic(0)
+            },
+        };
+        answer
+    }
+}
+
+// the printer's RLE: a control byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+// otherwise n + 1 literal bytes follow
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&value) = data.get(i) {
+                output.extend(std::iter::repeat_n(value, (control & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    output
+}
+
+fn encode_pgm(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut data = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+    data.extend_from_slice(pixels);
+    data
+}
+
+// 8-bit grayscale PNG, deflate with stored blocks keeps it dependency free
+fn encode_png(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for line in pixels.chunks_exact(width) {
+        raw.push(0);    // no filter
+        raw.extend_from_slice(line);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(u8::from(blocks.peek().is_none()));
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);     // bit depth, grayscale, deflate, no filter, no interlace
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // send a whole packet, returning the printer's ID and status bytes
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut body = vec![command, compressed as u8];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+
+        for byte in MAGIC.iter().chain(&body).chain(&checksum.to_le_bytes()) {
+            assert_eq!(printer.transfer(*byte), 0x00);
+        }
+        (printer.transfer(0x00), printer.transfer(0x00))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crabboy-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_print_pgm() {
+        let dir = temp_dir("printer-pgm");
+        let mut printer = Printer::new(&dir, PrintFormat::Pgm);
+        assert_eq!(send(&mut printer, COMMAND_INIT, false, &[]), (DEVICE_ID, 0x00));
+
+        // one band: the top row of tiles in color 3, the bottom one in color 1
+        let mut band = vec![0xFF; TILE_ROW_BYTES];
+        band.extend((0..TILE_ROW_BYTES).map(|i| if i % 2 == 0 { 0xFF } else { 0x00 }));
+        assert_eq!(send(&mut printer, COMMAND_DATA, false, &band), (DEVICE_ID, STATUS_UNPROCESSED));
+        assert_eq!(send(&mut printer, COMMAND_DATA, false, &[]).1, STATUS_UNPROCESSED);
+
+        // one sheet, no feed before and some after, identity palette
+        assert_eq!(send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x03, 0xE4, 0x40]).1, STATUS_PRINTING);
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[]).1, STATUS_PRINTING);
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[]).1, 0x00);
+
+        assert_eq!(printer.get_printed().len(), 1);
+        let image = fs::read(&printer.get_printed()[0]).unwrap();
+        let header = b"P5\n160 16\n255\n";
+        assert_eq!(&image[..header.len()], header);
+        let pixels = &image[header.len()..];
+        assert_eq!(pixels.len(), 160 * 16);
+        assert_eq!(pixels[0], 0x00);
+        assert_eq!(pixels[160 * 8], 0xAA);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_error() {
+        // a file where the directory should be
+        let dir = temp_dir("printer-error");
+        fs::write(&dir, b"").unwrap();
+        let mut printer = Printer::new(&dir, PrintFormat::Pgm);
+        send(&mut printer, COMMAND_DATA, false, &vec![0xFF; TILE_ROW_BYTES * 2]);
+        send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x01, 0xE4, 0x40]);
+        assert!(printer.get_last_error().is_some());
+        assert_eq!(printer.get_unsaved_pages(), 1);
+        assert!(printer.get_printed().is_empty());
+
+        fs::remove_file(&dir).unwrap();
+        printer.save_unsaved().unwrap();
+        assert_eq!(printer.get_unsaved_pages(), 0);
+        assert_eq!(printer.get_printed().len(), 1);
+        assert!(printer.take_last_error().is_some());
+        assert!(printer.get_last_error().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_error() {
+        let mut printer = Printer::new(temp_dir("printer-checksum"), PrintFormat::Png);
+        for byte in [0x88, 0x33, COMMAND_DATA, 0x00, 0x01, 0x00, 0x12, 0x00, 0x00] {
+            printer.transfer(byte);
+        }
+        assert_eq!(printer.transfer(0x00), DEVICE_ID);
+        assert_eq!(printer.transfer(0x00), STATUS_CHECKSUM_ERROR);
+        assert_eq!(send(&mut printer, COMMAND_STATUS, false, &[]).1, 0x00);
+    }
+
+    #[test]
+    fn test_compressed_png() {
+        let dir = temp_dir("printer-png");
+        let mut printer = Printer::new(&dir, PrintFormat::Png);
+        // runs of 0xFF covering two rows of tiles
+        let mut compressed = Vec::new();
+        for _ in 0..(TILE_ROW_BYTES * 2) / 128 {
+            compressed.extend_from_slice(&[0xFE, 0xFF]);    // 128 times 0xFF
+        }
+        assert_eq!(decompress(&compressed).len(), TILE_ROW_BYTES * 2);
+        assert_eq!(decompress(&[0x01, 0x12, 0x34, 0x80, 0x56]), vec![0x12, 0x34, 0x56, 0x56]);
+
+        send(&mut printer, COMMAND_DATA, true, &compressed);
+        send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x01, 0xE4, 0x40]);
+        let image = fs::read(&printer.get_printed()[0]).unwrap();
+        assert_eq!(&image[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        assert_eq!(&image[16..24], &[0, 0, 0, 160, 0, 0, 0, 16]);
+        assert_eq!(&image[image.len() - 8..], &[b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+use crate::dmgcpu::DMGCPU;
+use crate::state::StateError;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+// a literal run in a delta only ends at this many unchanged bytes, shorter gaps are cheaper inline
+const MIN_ZERO_RUN: usize = 4;
+
+/* ----- TYPE DECLARATIONS ----- */
+// ring buffer of snapshots taken every few frames: the newest is a full save state, every older
+// one is the XOR against the snapshot after it with the unchanged runs collapsed, so dropping the
+// oldest never needs the ones around it
+pub struct Rewind {
+    interval: u64,
+    depth: usize,
+    latest: Option<Snapshot>,
+    history: VecDeque<Snapshot>,    // deltas, oldest first
+}
+
+struct Snapshot {
+    frame: u64,
+    data: Vec<u8>,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Rewind {
+    // a snapshot every interval frames, keeping at most depth of them
+    pub fn new(interval: u64, depth: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            depth: depth.max(1),
+            latest: None,
+            history: VecDeque::with_capacity(depth),
+        }
+    }
+
+    pub fn get_interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn get_depth(&self) -> usize {
+        self.depth
+    }
+
+    // snapshots held, the newest included
+    pub fn len(&self) -> usize {
+        self.history.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    // bytes held by the snapshots
+    pub fn get_memory_usage(&self) -> usize {
+        self.history.iter().chain(&self.latest).map(|snapshot| snapshot.data.len()).sum()
+    }
+
+    // how far back rewind can go from the current frame
+    pub fn get_oldest_frame(&self) -> Option<u64> {
+        self.history.front().or(self.latest.as_ref()).map(|snapshot| snapshot.frame)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.history.clear();
+    }
+
+    // call once per frame: takes a snapshot once interval frames have passed since the last
+    // one, returning whether it did
+    pub fn capture(&mut self, cpu: &DMGCPU) -> bool {
+        let frame = cpu.get_frame_count();
+        match self.latest.as_ref().map(|latest| latest.frame) {
+            // something other than rewind, like loading a state, put the core back before the
+            // newest snapshot: the snapshots from there on belong to a timeline that's gone
+            Some(latest) if frame < latest => self.clear(),
+            Some(latest) if frame - latest < self.interval => return false,
+            _ => {},
+        }
+
+        let data = cpu.save_state();
+        if let Some(latest) = self.latest.take() {
+            self.history.push_back(Snapshot {
+                frame: latest.frame,
+                data: encode_delta(&latest.data, &data),
+            });
+            while self.history.len() >= self.depth {
+                self.history.pop_front();
+            }
+        }
+        self.latest = Some(Snapshot { frame, data });
+        true
+    }
+
+    // go back at least frames frames, to the newest snapshot that far back or the oldest one
+    // there is, returning how many frames were actually rewound. snapshots after it are dropped
+    // once it has loaded, a snapshot that fails to load leaves them all in place
+    pub fn rewind(&mut self, cpu: &mut DMGCPU, frames: u64) -> Result<u64, StateError> {
+        let Some(latest) = &self.latest else {
+            return Ok(0);
+        };
+        let current = cpu.get_frame_count();
+        let target = current.saturating_sub(frames);
+        let mut snapshot = Snapshot { frame: latest.frame, data: latest.data.clone() };
+        let mut kept = self.history.len();
+        while snapshot.frame > target && kept > 0 {
+            kept -= 1;
+            let delta = &self.history[kept];
+            snapshot = Snapshot {
+                frame: delta.frame,
+                data: apply_delta(&snapshot.data, &delta.data),
+            };
+        }
+
+        cpu.load_state(&snapshot.data)?;
+        self.history.truncate(kept);
+        let rewound = current.saturating_sub(snapshot.frame);
+        self.latest = Some(snapshot);
+        Ok(rewound)
+    }
+}
+
+// XOR of older against newer as alternating runs of unchanged and changed bytes, each run
+// length a LEB128 varint and the changed bytes stored as they are, after older's length
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+    let unchanged_run = |i: usize| (i..older.len()).take(MIN_ZERO_RUN).take_while(|&j| xor(j) == 0).count();
+
+    let mut delta = (older.len() as u32).to_le_bytes().to_vec();
+    let mut i = 0;
+    while i < older.len() {
+        let start = i;
+        while i < older.len() && xor(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+
+        let start = i;
+        while i < older.len() && (xor(i) != 0 || unchanged_run(i) < MIN_ZERO_RUN.min(older.len() - i)) {
+            i += 1;
+        }
+        write_varint(&mut delta, i - start);
+        delta.extend((start..i).map(xor));
+    }
+    delta
+}
+
+// older from newer and the delta encode_delta made of them
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let length = u32::from_le_bytes(delta[..4].try_into().unwrap()) as usize;
+    let mut older = newer.to_vec();
+    older.resize(length, 0);
+
+    let mut position = 4;
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position);
+        let count = read_varint(delta, &mut position);
+        for (byte, change) in older[i..i + count].iter_mut().zip(&delta[position..position + count]) {
+            *byte ^= change;
+        }
+        i += count;
+        position += count;
+    }
+    older
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    // a cartridge that halts with interrupts off, so only the hardware moves on
+    fn halted_cpu() -> DMGCPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x76;
+        let mut cpu = DMGCPU::with_model(Model::Dmg);
+        cpu.load_rom(&rom);
+        cpu.get_memory_mut().write_byte(0xFFFF, 0x00);
+        cpu
+    }
+
+    #[test]
+    fn test_delta() {
+        let newer: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut older = newer.clone();
+        older[3] ^= 0xFF;
+        older[5] = 0;
+        older[150..160].fill(0xAA);
+        let delta = encode_delta(&older, &newer);
+        assert!(delta.len() < 40);
+        assert_eq!(apply_delta(&newer, &delta), older);
+
+        // states of different sizes
+        assert_eq!(apply_delta(&newer, &encode_delta(&newer[..100], &newer)), &newer[..100]);
+        assert_eq!(apply_delta(&newer[..100], &encode_delta(&newer, &newer[..100])), newer);
+        assert_eq!(apply_delta(&newer, &encode_delta(&newer, &newer)), newer);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut cpu = halted_cpu();
+        let mut rewind = Rewind::new(2, 4);
+        let mut saved = Vec::new();
+        for _ in 0..12 {
+            if rewind.capture(&cpu) {
+                saved.push((cpu.get_frame_count(), cpu.save_state()));
+            }
+            cpu.run_frame();
+        }
+        assert_eq!(saved.len(), 6);
+        assert_eq!(rewind.len(), 4);
+        assert_eq!(rewind.get_oldest_frame(), Some(saved[2].0));
+        assert!(rewind.get_memory_usage() < saved[5].1.len() * 2);
+
+        // frame 12 back three frames lands on the snapshot at frame 8
+        let frame = cpu.get_frame_count();
+        assert_eq!(rewind.rewind(&mut cpu, 3), Ok(frame - saved[4].0));
+        assert_eq!(cpu.save_state(), saved[4].1);
+
+        // then as far back as there are snapshots
+        assert_eq!(rewind.rewind(&mut cpu, 100), Ok(saved[4].0 - saved[2].0));
+        assert_eq!(cpu.save_state(), saved[2].1);
+        assert_eq!(rewind.len(), 1);
+
+        // recording carries on from the rewound frame
+        cpu.run_frame();
+        assert!(!rewind.capture(&cpu));
+        cpu.run_frame();
+        assert!(rewind.capture(&cpu));
+        assert_eq!(rewind.len(), 2);
+    }
+
+    #[test]
+    fn test_timelines() {
+        let mut cpu = halted_cpu();
+        let mut rewind = Rewind::new(2, 8);
+        let start = cpu.save_state();
+        for _ in 0..6 {
+            rewind.capture(&cpu);
+            cpu.run_frame();
+        }
+        assert_eq!(rewind.len(), 3);
+
+        // loading an older state drops the snapshots taken after it
+        cpu.load_state(&start).unwrap();
+        assert!(rewind.capture(&cpu));
+        assert_eq!(rewind.len(), 1);
+        for _ in 0..4 {
+            cpu.run_frame();
+            rewind.capture(&cpu);
+        }
+        assert_eq!(rewind.len(), 3);
+
+        // a snapshot that doesn't load leaves the history alone
+        let mut rom = vec![0; 0x8000];
+        rom[0x0134] = b'X';
+        let mut other = DMGCPU::with_model(Model::Dmg);
+        other.load_rom(&rom);
+        assert_eq!(rewind.rewind(&mut other, 100), Err(StateError::RomMismatch));
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.rewind(&mut cpu, 100), Ok(4));
+        assert_eq!(cpu.save_state(), start);
+        assert_eq!(rewind.len(), 1);
+    }
+}
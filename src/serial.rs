@@ -0,0 +1,302 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::memory::INTERRUPT_SERIAL;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const SC_TRANSFER: u8 = 0x80;
+const SC_FAST: u8 = 0x02;       // CGB mode only
+const SC_INTERNAL: u8 = 0x01;
+
+const BIT_CYCLES: u16 = 512;        // 8192 Hz
+const FAST_BIT_CYCLES: u16 = 16;    // 262144 Hz
+
+/* ----- TYPE DECLARATIONS ----- */
+// whatever is plugged into the link port
+pub trait SerialDevice {
+    // this console clocks a transfer: takes the byte it shifts out, returns the byte shifted in
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    // polled while this console waits for the partner's clock, returns the partner's byte
+    // once it has clocked a whole one, and takes this console's byte in exchange
+    fn external_transfer(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+
+    // clock cycles passing on this console, for devices that keep in step with it
+    fn tick(&mut self, _cycles: u8) {}
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    cgb_mode: bool,
+    incoming: u8,       // partner's byte, shifted into SB a bit at a time
+    bits: u8,           // bits left in an internally clocked transfer
+    cycles: u16,        // clock cycles into the current bit
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+// records every byte the console sends, clones share the same recording
+#[derive(Clone, Default)]
+pub struct SerialCapture {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+// a device the frontend keeps a handle on, to look at it while the console owns it
+impl<T: SerialDevice> SerialDevice for Rc<RefCell<T>> {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.borrow_mut().transfer(outgoing)
+    }
+
+    fn external_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        self.borrow_mut().external_transfer(outgoing)
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.borrow_mut().tick(cycles)
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            cgb_mode: false,
+            incoming: 0xFF,
+            bits: 0,
+            cycles: 0,
+            device: None,
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    // plug in a link partner, returning the one it replaces
+    pub fn attach(&mut self, device: Box<dyn SerialDevice>) -> Option<Box<dyn SerialDevice>> {
+        self.device.replace(device)
+    }
+
+    pub fn detach(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    // advance by clock cycles, returns the interrupts requested
+    pub fn tick(&mut self, cycles: u8) -> u8 {
+        if let Some(device) = self.device.as_mut() {
+            device.tick(cycles);
+        }
+
+        if self.sc & SC_TRANSFER == 0 {
+            return 0;
+        }
+
+        if self.sc & SC_INTERNAL == 0 {
+            let incoming = self.device.as_mut().and_then(|device| device.external_transfer(self.sb));
+            return match incoming {
+                Some(incoming) => {
+                    self.sb = incoming;
+                    self.finish()
+                },
+                None => 0,
+            };
+        }
+
+        let period = if self.cgb_mode && self.sc & SC_FAST != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES };
+        self.cycles += cycles as u16;
+        while self.cycles >= period && self.bits > 0 {
+            self.cycles -= period;
+            self.bits -= 1;
+            self.sb = (self.sb << 1) | ((self.incoming >> self.bits) & 0x01);
+        }
+
+        if self.bits == 0 { self.finish() } else { 0 }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF01 => self.sb,
+            0xFF02 if self.cgb_mode => 0x7C | self.sc,
+            0xFF02 => 0x7E | self.sc,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                let mask = if self.cgb_mode { SC_TRANSFER | SC_FAST | SC_INTERNAL } else { SC_TRANSFER | SC_INTERNAL };
+                self.sc = value & mask;
+                if self.sc & (SC_TRANSFER | SC_INTERNAL) == SC_TRANSFER | SC_INTERNAL {
+                    self.start();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // the port itself, whatever is plugged into it stays where it is
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_u8(self.incoming);
+        state.write_u8(self.bits);
+        state.write_u16(self.cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()?;
+        self.incoming = state.read_u8()?;
+        self.bits = state.read_u8()?;
+        self.cycles = state.read_u16()?;
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    // the partner answers right away, its byte is shifted in over the next eight bits
+    fn start(&mut self) {
+        // with nothing connected the input line floats high
+        self.incoming = match self.device.as_mut() {
+            Some(device) => device.transfer(self.sb),
+            None => 0xFF,
+        };
+        self.bits = 8;
+        self.cycles = 0;
+    }
+
+    fn finish(&mut self) -> u8 {
+        self.sc &= !SC_TRANSFER;
+        INTERRUPT_SERIAL
+    }
+}
+
+impl SerialCapture {
+    pub fn new() -> SerialCapture {
+        SerialCapture::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.output.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.output.borrow().is_empty()
+    }
+
+    // the recording as text, test ROMs print ASCII
+    pub fn get_output(&self) -> String {
+        String::from_utf8_lossy(&self.output.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.output.borrow_mut().clear();
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    // nothing answers, so the console reads the line floating high
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.output.borrow_mut().push(outgoing);
+        0xFF
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, outgoing: u8) -> u8 {
+            !outgoing
+        }
+
+        fn external_transfer(&mut self, _outgoing: u8) -> Option<u8> {
+            Some(0x42)
+        }
+    }
+
+    fn run(serial: &mut Serial, cycles: u32) -> u8 {
+        let mut interrupts = 0;
+        for _ in 0..cycles / 4 {
+            interrupts |= serial.tick(4);
+        }
+        interrupts
+    }
+
+    #[test]
+    fn test_no_partner() {
+        let mut serial = Serial::new();
+        serial.write_register(0xFF01, 0x12);
+        serial.write_register(0xFF02, 0x81);
+        assert_eq!(serial.read_register(0xFF02), 0xFF);
+
+        // one bit every 512 cycles
+        assert_eq!(run(&mut serial, 512 * 4), 0);
+        assert_eq!(serial.read_register(0xFF01), 0x2F);
+        assert_eq!(run(&mut serial, 512 * 4 - 4), 0);
+        assert_eq!(run(&mut serial, 4), INTERRUPT_SERIAL);
+        assert_eq!(serial.read_register(0xFF01), 0xFF);
+        assert_eq!(serial.read_register(0xFF02), 0x7F);
+    }
+
+    #[test]
+    fn test_fast_clock() {
+        let mut serial = Serial::new();
+        serial.set_cgb_mode(true);
+        serial.attach(Box::new(Echo));
+        serial.write_register(0xFF01, 0x0F);
+        serial.write_register(0xFF02, 0x83);
+        assert_eq!(serial.read_register(0xFF02), 0xFF);
+        assert_eq!(run(&mut serial, 16 * 8), INTERRUPT_SERIAL);
+        assert_eq!(serial.read_register(0xFF01), 0xF0);
+
+        // the fast bit is ignored outside CGB mode
+        serial.set_cgb_mode(false);
+        serial.write_register(0xFF02, 0x83);
+        assert_eq!(serial.read_register(0xFF02), 0xFF);
+        assert_eq!(run(&mut serial, 16 * 8), 0);
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture = SerialCapture::new();
+        let mut serial = Serial::new();
+        serial.attach(Box::new(capture.clone()));
+        for byte in b"ok" {
+            serial.write_register(0xFF01, *byte);
+            serial.write_register(0xFF02, 0x81);
+            run(&mut serial, 512 * 8);
+        }
+        assert_eq!(capture.get_output(), "ok");
+        assert_eq!(serial.read_register(0xFF01), 0xFF);
+        capture.clear();
+        assert!(capture.is_empty());
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut serial = Serial::new();
+        serial.write_register(0xFF02, 0x80);
+        assert_eq!(run(&mut serial, 0x10000), 0);
+
+        serial.attach(Box::new(Echo));
+        assert_eq!(serial.tick(4), INTERRUPT_SERIAL);
+        assert_eq!(serial.read_register(0xFF01), 0x42);
+        assert!(serial.detach().is_some());
+    }
+}
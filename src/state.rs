@@ -0,0 +1,270 @@
+use std::fmt;
+use crate::model::Model;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const MAGIC: &[u8; 8] = b"CRABSAVE";
+
+// states with a different major version can't be loaded, minor versions only ever add
+// sections or append fields to a section, which older versions skip
+pub const STATE_VERSION_MAJOR: u16 = 1;
+pub const STATE_VERSION_MINOR: u16 = 0;
+
+/* ----- TYPE DECLARATIONS ----- */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StateError {
+    NotAState,
+    Version { found: u16, supported: u16 },
+    Truncated,
+    ModelMismatch { found: Model, expected: Model },
+    RomMismatch,
+    Invalid(&'static str),
+}
+
+// the header in front of every state
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StateHeader {
+    pub major: u16,
+    pub minor: u16,
+    pub model: Model,
+    pub cartridge: Vec<u8>,     // cartridge header bytes 0x0134-0x014F, to catch the wrong ROM
+}
+
+// little endian fields, grouped into sections that are each a 4 byte tag and a 32-bit length
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::Version { found, supported } => {
+                write!(f, "save state format version {} is not supported, this build reads version {}", found, supported)
+            },
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::ModelMismatch { found, expected } => {
+                write!(f, "save state is for {:?} hardware, not {:?}", found, expected)
+            },
+            StateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl StateHeader {
+    pub fn new(model: Model, cartridge: &[u8]) -> StateHeader {
+        StateHeader {
+            major: STATE_VERSION_MAJOR,
+            minor: STATE_VERSION_MINOR,
+            model,
+            cartridge: cartridge.to_vec(),
+        }
+    }
+
+    // just the header of a state, checking that this build can load the rest
+    pub fn parse(state: &[u8]) -> Result<StateHeader, StateError> {
+        StateReader::new(state).read_header()
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_header(&mut self, header: &StateHeader) {
+        self.data.extend_from_slice(MAGIC);
+        self.write_u16(header.major);
+        self.write_u16(header.minor);
+        self.write_u8(model_id(header.model));
+        self.write_vec(&header.cartridge);
+    }
+
+    // everything written by fields goes into one section
+    pub fn section(&mut self, tag: &[u8; 4], fields: impl FnOnce(&mut StateWriter)) {
+        self.data.extend_from_slice(tag);
+        let start = self.data.len();
+        self.write_u32(0);
+        fields(self);
+        let length = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // a fixed size block, read back with read_bytes into a buffer of the same size
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // a block preceded by its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    pub fn read_header(&mut self) -> Result<StateHeader, StateError> {
+        if self.data.len() < MAGIC.len() || &self.data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        self.position = MAGIC.len();
+        let major = self.read_u16()?;
+        if major != STATE_VERSION_MAJOR {
+            return Err(StateError::Version { found: major, supported: STATE_VERSION_MAJOR });
+        }
+        let minor = self.read_u16()?;
+        let model = *Model::ALL.get(self.read_u8()? as usize).ok_or(StateError::Invalid("model"))?;
+        let cartridge = self.read_vec()?;
+        Ok(StateHeader { major, minor, model, cartridge })
+    }
+
+    // the next section's tag and a reader over its fields, None at the end of the state
+    pub fn next_section(&mut self) -> Result<Option<([u8; 4], StateReader<'a>)>, StateError> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let tag = self.take(4)?.try_into().unwrap();
+        let length = self.read_u32()? as usize;
+        Ok(Some((tag, StateReader::new(self.take(length)?))))
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let length = self.read_u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /* ----- PRIVATE ----- */
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(length).ok_or(StateError::Truncated)?;
+        let bytes = self.data.get(self.position..end).ok_or(StateError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+}
+
+pub(crate) fn model_id(model: Model) -> u8 {
+    Model::ALL.iter().position(|&m| m == model).unwrap() as u8
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections() {
+        let mut writer = StateWriter::new();
+        writer.write_header(&StateHeader::new(Model::Cgb, b"TITLE"));
+        writer.section(b"ONE ", |state| {
+            state.write_u16(0x1234);
+            state.write_bool(true);
+        });
+        writer.section(b"TWO ", |state| state.write_vec(&[1, 2, 3]));
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        let header = reader.read_header().unwrap();
+        assert_eq!(header, StateHeader::new(Model::Cgb, b"TITLE"));
+
+        // sections can be skipped, and fields a section has beyond the known ones are ignored
+        let (tag, mut one) = reader.next_section().unwrap().unwrap();
+        assert_eq!(&tag, b"ONE ");
+        assert_eq!(one.read_u16(), Ok(0x1234));
+        let (tag, mut two) = reader.next_section().unwrap().unwrap();
+        assert_eq!(&tag, b"TWO ");
+        assert_eq!(two.read_vec(), Ok(vec![1, 2, 3]));
+        assert_eq!(two.read_u8(), Err(StateError::Truncated));
+        assert!(reader.next_section().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_header_errors() {
+        assert_eq!(StateHeader::parse(b"RIFF...."), Err(StateError::NotAState));
+
+        let mut writer = StateWriter::new();
+        let mut header = StateHeader::new(Model::Dmg, &[]);
+        header.major = STATE_VERSION_MAJOR + 1;
+        writer.write_header(&header);
+        let error = StateHeader::parse(&writer.into_bytes()).unwrap_err();
+        assert_eq!(error, StateError::Version { found: STATE_VERSION_MAJOR + 1, supported: STATE_VERSION_MAJOR });
+        assert!(error.to_string().contains("version 2 is not supported"));
+
+        // a newer minor version loads
+        let mut writer = StateWriter::new();
+        let mut header = StateHeader::new(Model::Dmg, &[]);
+        header.minor = STATE_VERSION_MINOR + 1;
+        writer.write_header(&header);
+        assert_eq!(StateHeader::parse(&writer.into_bytes()), Ok(header));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::memory::INTERRUPT_TIMER;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const TAC_ENABLE: u8 = 0x04;
+const TAC_CLOCK: u8 = 0x03;
+
+// system counter bit whose falling edge clocks TIMA, indexed by TAC bits 0-1
+const TAC_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+
+/* ----- TYPE DECLARATIONS ----- */
+pub struct Timer {
+    counter: u16,       // system counter, DIV is the upper byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow: bool,     // TIMA wrapped during the last M-cycle and reads 0 until it is reloaded
+    reloading: bool,    // TIMA was reloaded from TMA during the last M-cycle
+    cycles: u16,        // clock cycles not yet making up an M-cycle
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
+            cycles: 0,
+        }
+    }
+
+    // the boot ROM leaves the system counter running with DIV at a model specific value
+    pub fn boot(&mut self, div: u8) {
+        self.counter = (div as u16) << 8;
+    }
+
+    // the whole system counter, other units are clocked by its bits
+    pub fn get_counter(&self) -> u16 {
+        self.counter
+    }
+
+    // advance by clock cycles, returns the interrupts requested
+    pub fn tick(&mut self, cycles: u8) -> u8 {
+        let mut interrupts = 0;
+        self.cycles += cycles as u16;
+        while self.cycles >= 4 {
+            self.cycles -= 4;
+            interrupts |= self.step();
+        }
+        interrupts
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF04 => {
+                // clearing the counter is a falling edge if the selected bit was set
+                let signal = self.signal();
+                self.counter = 0;
+                self.check_edge(signal);
+            },
+            // a write in the cycle after the overflow cancels the reload and the interrupt,
+            // one in the reload cycle itself is overwritten by TMA
+            0xFF05 if !self.reloading => {
+                self.tima = value;
+                self.overflow = false;
+            },
+            0xFF06 => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            },
+            0xFF07 => {
+                // disabling the timer or switching to a bit that is clear also increments TIMA
+                let signal = self.signal();
+                self.tac = value & (TAC_ENABLE | TAC_CLOCK);
+                self.check_edge(signal);
+            },
+            _ => {},
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_bool(self.overflow);
+        state.write_bool(self.reloading);
+        state.write_u16(self.cycles);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+        self.overflow = state.read_bool()?;
+        self.reloading = state.read_bool()?;
+        self.cycles = state.read_u16()?;
+        Ok(())
+    }
+
+    /* ----- PRIVATE ----- */
+    fn step(&mut self) -> u8 {
+        let mut interrupts = 0;
+        self.reloading = false;
+        if self.overflow {
+            self.tima = self.tma;
+            self.overflow = false;
+            self.reloading = true;
+            interrupts |= INTERRUPT_TIMER;
+        }
+
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.check_edge(signal);
+        interrupts
+    }
+
+    // the enable bit ANDed with the selected counter bit, TIMA counts its falling edges
+    fn signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & TAC_BITS[(self.tac & TAC_CLOCK) as usize] != 0
+    }
+
+    fn check_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow |= overflow;
+        }
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        timer.boot(0xAB);
+        assert_eq!(timer.read_register(0xFF04), 0xAB);
+        timer.tick(252);
+        timer.tick(4);
+        assert_eq!(timer.read_register(0xFF04), 0xAC);
+        timer.write_register(0xFF04, 0x55);
+        assert_eq!(timer.read_register(0xFF04), 0x00);
+        assert_eq!(timer.read_register(0xFF07), 0xF8);
+    }
+
+    #[test]
+    fn test_frequencies() {
+        for (tac, cycles) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)] {
+            let mut timer = Timer::new();
+            timer.write_register(0xFF07, tac);
+            for _ in 0..cycles / 4 - 1 {
+                timer.tick(4);
+            }
+            assert_eq!(timer.read_register(0xFF05), 0x00);
+            timer.tick(4);
+            assert_eq!(timer.read_register(0xFF05), 0x01, "TAC {:02X}", tac);
+        }
+    }
+
+    #[test]
+    fn test_overflow_reload() {
+        let mut timer = Timer::new();
+        timer.write_register(0xFF06, 0x80);
+        timer.write_register(0xFF05, 0xFF);
+        timer.write_register(0xFF07, 0x05);
+        for _ in 0..4 {
+            assert_eq!(timer.tick(4), 0);
+        }
+
+        // TIMA reads 0 for one M-cycle before TMA is loaded and the interrupt fires
+        assert_eq!(timer.read_register(0xFF05), 0x00);
+        assert_eq!(timer.tick(4), INTERRUPT_TIMER);
+        assert_eq!(timer.read_register(0xFF05), 0x80);
+
+        // writes to TIMA in the reload cycle are lost, TMA writes go through to TIMA
+        timer.write_register(0xFF05, 0x10);
+        assert_eq!(timer.read_register(0xFF05), 0x80);
+        timer.write_register(0xFF06, 0x90);
+        assert_eq!(timer.read_register(0xFF05), 0x90);
+    }
+
+    #[test]
+    fn test_overflow_cancel() {
+        let mut timer = Timer::new();
+        timer.write_register(0xFF06, 0x80);
+        timer.write_register(0xFF05, 0xFF);
+        timer.write_register(0xFF07, 0x05);
+        timer.tick(16);
+
+        // writing TIMA while it reads 0 cancels the reload and the interrupt
+        timer.write_register(0xFF05, 0x42);
+        assert_eq!(timer.tick(4), 0);
+        assert_eq!(timer.read_register(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn test_glitch_increments() {
+        // resetting DIV while the selected bit is set is a falling edge
+        let mut timer = Timer::new();
+        timer.write_register(0xFF07, 0x05);
+        timer.tick(8);
+        timer.write_register(0xFF04, 0);
+        assert_eq!(timer.read_register(0xFF05), 0x01);
+
+        // so is disabling the timer
+        timer.tick(8);
+        timer.write_register(0xFF07, 0x01);
+        assert_eq!(timer.read_register(0xFF05), 0x02);
+
+        // and selecting a bit that is clear
+        timer.write_register(0xFF07, 0x05);
+        timer.write_register(0xFF07, 0x04);
+        assert_eq!(timer.read_register(0xFF05), 0x03);
+
+        // but not while the selected bit is clear
+        timer.write_register(0xFF04, 0);
+        assert_eq!(timer.read_register(0xFF05), 0x03);
+    }
+}
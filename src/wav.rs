@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/* ----- CONSTANT DECLARATIONS ----- */
+const HEADER_SIZE: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/* ----- TYPE DECLARATIONS ----- */
+// 16-bit PCM WAV file written as samples arrive, the header's sizes are filled in by finish
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_size: u32,
+}
+
+/* ----- IMPL DEFINITIONS ----- */
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<WavWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;     // integer PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    // interleaved samples, a whole number of frames
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    // fill in the sizes, the file is complete even if more samples follow later
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/* ---------------------------------- TESTS ---------------------------------- */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_header() {
+        let path = std::env::temp_dir().join(format!("crabboy_wav_{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 48_000, 2).unwrap();
+        wav.write_samples(&[1, -1, 0x1234, 0]).unwrap();
+        wav.finish().unwrap();
+        wav.write_samples(&[7, 7]).unwrap();
+        wav.finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 12);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([data[22], data[23]]), 2);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 48_000);
+        assert_eq!(u32::from_le_bytes(data[28..32].try_into().unwrap()), 192_000);
+        assert_eq!(u16::from_le_bytes([data[32], data[33]]), 4);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 12);
+        assert_eq!(&data[44..50], &[0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crabboy::dmgcpu::DMGCPU;
+use crabboy::headless::{HeadlessRunner, Outcome};
+
+// every .gb and .gbc file under a directory
+fn find_roms(path: &Path, roms: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        for entry in fs::read_dir(path).unwrap() {
+            find_roms(&entry.unwrap().path(), roms);
+        }
+    } else if matches!(path.extension().and_then(|extension| extension.to_str()), Some("gb" | "gbc")) {
+        roms.push(path.to_path_buf());
+    }
+}
+
+// blargg's suites and the like, from the directory or ROM CRABBOY_TEST_ROMS names. the ROMs
+// can't be shipped here, so without it there is nothing to run
+#[test]
+fn test_roms() {
+    let Some(path) = std::env::var_os("CRABBOY_TEST_ROMS") else {
+        eprintln!("CRABBOY_TEST_ROMS isn't set, skipping the test ROMs");
+        return;
+    };
+    let mut roms = Vec::new();
+    find_roms(Path::new(&path), &mut roms);
+    roms.sort();
+    assert!(!roms.is_empty(), "no ROMs found in {}", Path::new(&path).display());
+
+    let mut failed = Vec::new();
+    for path in roms {
+        let rom = fs::read(&path).unwrap();
+        let mut runner = HeadlessRunner::new(DMGCPU::from_rom(&rom));
+        let outcome = runner.run();
+        if outcome != Outcome::Passed {
+            failed.push(format!("{}: {:?}\n{}", path.display(), outcome, runner.get_output()));
+        }
+    }
+    assert!(failed.is_empty(), "{} test ROMs didn't pass\n{}", failed.len(), failed.join("\n"));
+}